@@ -55,4 +55,118 @@ pub enum PerpError {
 
     #[msg("Funding was already settled for the period")]
     FundingAlreadySettled,
+
+    #[msg("Remaining accounts must be [market, oracle] pairs with no duplicate markets")]
+    InvalidRemainingAccounts,
+
+    #[msg("A market account required for the margin calculation is missing")]
+    MarketAccountMissing,
+
+    #[msg("An insurance fund withdrawal request is already pending")]
+    WithdrawRequestPending,
+
+    #[msg("No insurance fund withdrawal request is pending")]
+    NoWithdrawRequest,
+
+    #[msg("Insurance fund unstaking period has not elapsed")]
+    UnstakingPeriodNotElapsed,
+
+    #[msg("Account is not a valid auto-deleveraging counterparty")]
+    InvalidAdlCounterparty,
+
+    #[msg("Auto-deleveraging counterparties are not ordered by descending ADL score")]
+    AdlCandidatesOutOfOrder,
+
+    #[msg("Per-slot liquidation limit reached for this market")]
+    LiquidationLimitReached,
+
+    #[msg("Liquidator cannot liquidate their own account")]
+    CannotLiquidateSelf,
+
+    #[msg("Order book side is full")]
+    OrderBookFull,
+
+    #[msg("Order not found")]
+    OrderNotFound,
+
+    #[msg("The user account of a crossed maker order is missing")]
+    MakerAccountMissing,
+
+    #[msg("Order would match against the user's own resting order")]
+    CannotMatchOwnOrder,
+
+    #[msg("User is not registered as a JIT maker")]
+    MakerNotRegistered,
+
+    #[msg("Taker auction has ended")]
+    AuctionEnded,
+
+    #[msg("Taker auction is still open to makers")]
+    AuctionInProgress,
+
+    #[msg("Maker price is worse than the vAMM quote")]
+    PriceWorseThanAmm,
+
+    #[msg("No free trigger order slot")]
+    TriggerOrdersFull,
+
+    #[msg("Trigger order condition is not met")]
+    TriggerConditionNotMet,
+
+    #[msg("Reduce-only order would increase the position")]
+    ReduceOnlyIncreasesPosition,
+
+    #[msg("Post-only order would take liquidity")]
+    PostOnlyWouldTake,
+
+    #[msg("Fill-or-kill order could not be filled in full")]
+    FillOrKillNotFilled,
+
+    #[msg("Order has expired")]
+    OrderExpired,
+
+    #[msg("Maximum number of TWAP orders reached")]
+    TwapOrdersFull,
+
+    #[msg("TWAP order's next slice is not due yet")]
+    TwapSliceNotReady,
+
+    #[msg("Maximum number of limit orders reached")]
+    LimitOrdersFull,
+
+    #[msg("vAMM price has not crossed the limit price")]
+    LimitPriceNotCrossed,
+
+    #[msg("Fee pool cannot cover the vAMM adjustment or withdrawal")]
+    InsufficientFeePool,
+
+    #[msg("Repeg must move the mark price toward the oracle price")]
+    InvalidRepeg,
+
+    #[msg("Fill would push the mark price too far from the oracle price")]
+    MarkOracleDivergenceExceeded,
+
+    #[msg("Insurance fund vault is empty while shares are outstanding")]
+    InsuranceFundDepleted,
+
+    #[msg("Order is smaller than the market's minimum order size")]
+    OrderTooSmall,
+
+    #[msg("Order book account is required to cancel the user's resting orders")]
+    OrderBookMissing,
+
+    #[msg("Order expiry must be a positive timestamp")]
+    InvalidOrderExpiry,
+
+    #[msg("Account already has the current layout")]
+    AccountAlreadyMigrated,
+
+    #[msg("Market account has not been migrated to the current layout")]
+    MarketNotMigrated,
+
+    #[msg("Auto-deleveraging counterparty scores below its side's average position")]
+    AdlScoreBelowSide,
+
+    #[msg("Fill price is too far from the oracle price")]
+    FillPriceOutsideOracleBand,
 }
@@ -0,0 +1,304 @@
+use anchor_lang::prelude::*;
+use anchor_lang::system_program;
+use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+use crate::state::constants::{
+    BPS_PRECISION, MARKET_SEED, PROGRAM_SEED, RATIO_PRECISION, VAULT_SEED,
+};
+use crate::state::market::{BankruptcyMode, Market};
+use crate::state::state::State;
+use crate::error::PerpError;
+use crate::instructions::create_market::initialize_market_defaults;
+use crate::validation::validate_oracle_price;
+
+/// Context for admin updates to an existing market's parameters.
+#[derive(Accounts)]
+pub struct AdminUpdateMarket<'info> {
+    pub admin: Signer<'info>,
+
+    /// Program state (must match the admin).
+    #[account(has_one = admin)]
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.load()?.market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+}
+
+/// Sets the share of positive unrealized PnL excluded from a user's equity.
+pub fn handle_update_market_unrealized_pnl_haircut(
+    ctx: Context<AdminUpdateMarket>,
+    unrealized_pnl_haircut: u64,
+) -> Result<()> {
+    require_gte!(RATIO_PRECISION, unrealized_pnl_haircut, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.unrealized_pnl_haircut = unrealized_pnl_haircut;
+
+    Ok(())
+}
+
+/// Sets the share of trade fees allotted to the insurance fund.
+pub fn handle_update_market_insurance_fee_share(
+    ctx: Context<AdminUpdateMarket>,
+    insurance_fee_share: u64,
+) -> Result<()> {
+    require_gte!(RATIO_PRECISION, insurance_fee_share, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.insurance_fee_share = insurance_fee_share;
+
+    Ok(())
+}
+
+/// Sets how the market absorbs deficits the insurance fund cannot cover.
+pub fn handle_update_market_bankruptcy_mode(
+    ctx: Context<AdminUpdateMarket>,
+    bankruptcy_mode: BankruptcyMode,
+) -> Result<()> {
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.bankruptcy_mode = bankruptcy_mode as u8;
+
+    Ok(())
+}
+
+/// Sets the partial liquidation target buffer and the per-slot liquidation limit.
+pub fn handle_update_market_liquidation_params(
+    ctx: Context<AdminUpdateMarket>,
+    liquidation_margin_buffer_ratio: u64,
+    max_liquidation_base_per_slot: u128,
+) -> Result<()> {
+    require_gte!(RATIO_PRECISION, liquidation_margin_buffer_ratio, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.liquidation_margin_buffer_ratio = liquidation_margin_buffer_ratio;
+    market.max_liquidation_base_per_slot = max_liquidation_base_per_slot;
+
+    Ok(())
+}
+
+/// Sets the share of the liquidation fee paid to the liquidator.
+pub fn handle_update_market_liquidator_fee_share(
+    ctx: Context<AdminUpdateMarket>,
+    liquidator_fee_share: u64,
+) -> Result<()> {
+    require_gte!(RATIO_PRECISION, liquidator_fee_share, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.liquidator_fee_share = liquidator_fee_share;
+
+    Ok(())
+}
+
+/// Sets the maximum funding rate per period as a share of the oracle TWAP.
+pub fn handle_update_market_max_funding_rate(
+    ctx: Context<AdminUpdateMarket>,
+    max_funding_rate: u64,
+) -> Result<()> {
+    require_gte!(RATIO_PRECISION, max_funding_rate, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.max_funding_rate = max_funding_rate;
+
+    Ok(())
+}
+
+/// Sets the fee pool target and bounds of the vAMM's formulaic adjustment on funding updates.
+pub fn handle_update_market_amm_adjustment_params(
+    ctx: Context<AdminUpdateMarket>,
+    amm_fee_pool_target: u64,
+    max_amm_adjustment_ratio: u64,
+    min_k_constant: u128,
+    max_k_constant: u128,
+) -> Result<()> {
+    require_gt!(RATIO_PRECISION, max_amm_adjustment_ratio, PerpError::InvalidAmount);
+    require_gte!(max_k_constant, min_k_constant, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.amm_fee_pool_target = amm_fee_pool_target;
+    market.max_amm_adjustment_ratio = max_amm_adjustment_ratio;
+    market.min_k_constant = min_k_constant;
+    market.max_k_constant = max_k_constant;
+
+    Ok(())
+}
+
+/// Sets the vAMM's base spread and the cap on each side's spread.
+pub fn handle_update_market_spread_params(
+    ctx: Context<AdminUpdateMarket>,
+    base_spread: u64,
+    max_spread: u64,
+) -> Result<()> {
+    require_gte!(max_spread, base_spread, PerpError::InvalidAmount);
+    require_gt!(RATIO_PRECISION, max_spread, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.base_spread = base_spread;
+    market.max_spread = max_spread;
+
+    Ok(())
+}
+
+/// Sets the maximum mark-oracle divergence a vAMM fill may leave, zero for no limit.
+pub fn handle_update_market_max_mark_oracle_divergence(
+    ctx: Context<AdminUpdateMarket>,
+    max_mark_oracle_divergence_bps: u64,
+) -> Result<()> {
+    require_gte!(
+        BPS_PRECISION,
+        max_mark_oracle_divergence_bps as u128,
+        PerpError::InvalidAmount
+    );
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.max_mark_oracle_divergence_bps = max_mark_oracle_divergence_bps;
+
+    Ok(())
+}
+
+/// Sets the discount to oracle at which liquidators take over positions.
+pub fn handle_update_market_liquidation_transfer_discount(
+    ctx: Context<AdminUpdateMarket>,
+    liquidation_transfer_discount: u64,
+) -> Result<()> {
+    require_gt!(RATIO_PRECISION, liquidation_transfer_discount, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.liquidation_transfer_discount = liquidation_transfer_discount;
+
+    Ok(())
+}
+
+/// Sets the smallest order size accepted on the market's order book.
+pub fn handle_update_market_min_order_size(
+    ctx: Context<AdminUpdateMarket>,
+    min_order_size: u128,
+) -> Result<()> {
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.min_order_size = min_order_size;
+
+    Ok(())
+}
+
+/// Context for the admin withdrawing protocol fees from a market's fee pool.
+#[derive(Accounts)]
+pub struct WithdrawFeePool<'info> {
+    pub admin: Signer<'info>,
+
+    /// Program state (must match the admin).
+    #[account(has_one = admin)]
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.load()?.market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, program_state.usdc_mint.key().as_ref()],
+        bump
+    )]
+    pub collateral_vault: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub recipient: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+/// Withdraws `amount` from the market's fee pool, which must keep enough to back the vAMM's
+/// own PnL exposure.
+pub fn handle_withdraw_fee_pool(ctx: Context<WithdrawFeePool>, amount: u64) -> Result<()> {
+    require_gt!(amount, 0, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    let withdrawable = market
+        .fee_pool
+        .saturating_sub(market.get_amm_pnl_exposure()?);
+    require_gte!(withdrawable, amount, PerpError::InsufficientFeePool);
+    market.fee_pool -= amount;
+    drop(market);
+
+    let signer_seeds = &[&PROGRAM_SEED[..], &[ctx.accounts.program_state.bump]];
+    let signer = &[&signer_seeds[..]];
+
+    let cpi_accounts = Transfer {
+        from: ctx.accounts.collateral_vault.to_account_info(),
+        to: ctx.accounts.recipient.to_account_info(),
+        authority: ctx.accounts.program_state.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer);
+    token::transfer(cpi_ctx, amount)
+}
+
+/// Context for the admin growing a market account created with the original market layout.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct MigrateMarket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    /// Program state (must match the admin).
+    #[account(has_one = admin)]
+    pub program_state: Account<'info, State>,
+
+    /// CHECK: Market account in the original layout, which `AccountLoader` cannot load until it
+    /// has grown. Its address and owner are checked here, its discriminator in the handler.
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump,
+        owner = crate::ID
+    )]
+    pub market: UncheckedAccount<'info>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Grows a market account created with the original layout to the current one, the admin paying
+/// the extra rent, and sets the appended fields to their defaults. Open interest starts at zero
+/// and is rebuilt from each position as `migrate_user` rewrites the accounts holding them.
+pub fn handle_migrate_market(ctx: Context<MigrateMarket>, _market_index: u16) -> Result<()> {
+    let market_info = ctx.accounts.market.to_account_info();
+    let new_len = 8 + std::mem::size_of::<Market>();
+    require_gt!(new_len, market_info.data_len(), PerpError::AccountAlreadyMigrated);
+    require!(
+        market_info.try_borrow_data()?.starts_with(&Market::DISCRIMINATOR),
+        ErrorCode::AccountDiscriminatorMismatch
+    );
+
+    let rent_shortfall = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(market_info.lamports());
+    if rent_shortfall > 0 {
+        let cpi_accounts = system_program::Transfer {
+            from: ctx.accounts.admin.to_account_info(),
+            to: market_info.clone(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.system_program.to_account_info(), cpi_accounts);
+        system_program::transfer(cpi_ctx, rent_shortfall)?;
+    }
+    market_info.realloc(new_len, true)?;
+
+    let clock = Clock::get()?;
+    let oracle_price = validate_oracle_price(&ctx.accounts.oracle_price_feed, &clock)?;
+
+    let mut data = market_info.try_borrow_mut_data()?;
+    let market: &mut Market = bytemuck::from_bytes_mut(&mut data[8..new_len]);
+    require_keys_eq!(
+        market.oracle_price_feed,
+        ctx.accounts.oracle_price_feed.key(),
+        PerpError::InvalidOraclePrice
+    );
+
+    market.open_interest_base = 0;
+    initialize_market_defaults(market, oracle_price, clock.unix_timestamp)
+}
@@ -0,0 +1,79 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::MARKET_SEED;
+use crate::state::market::Market;
+use crate::state::state::State;
+use crate::error::PerpError;
+use crate::instructions::admin::AdminUpdateMarket;
+use crate::math::amm::calculate_repeg_cost;
+use crate::validation::validate_oracle_price;
+
+/// Context for admin adjustments to a market's vAMM.
+#[derive(Accounts)]
+pub struct AdminUpdateAmm<'info> {
+    pub admin: Signer<'info>,
+
+    /// Program state (must match the admin).
+    #[account(has_one = admin)]
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market.load()?.market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
+}
+
+/// Moves the vAMM peg to `new_peg_multiplier`, which must bring the mark price closer to the
+/// oracle price without overshooting it. The cost of the repeg to the vAMM, given the users'
+/// net position against it, is charged to the fee pool.
+pub fn handle_repeg_amm(ctx: Context<AdminUpdateAmm>, new_peg_multiplier: u128) -> Result<()> {
+    require_gt!(new_peg_multiplier, 0, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    require_keys_eq!(
+        market.oracle_price_feed,
+        ctx.accounts.oracle_price_feed.key(),
+        PerpError::InvalidOraclePrice
+    );
+    let clock = Clock::get()?;
+    let oracle_price = validate_oracle_price(&ctx.accounts.oracle_price_feed, &clock)?;
+    market.update_oracle_price_twap(oracle_price, clock.unix_timestamp)?;
+    market.update_mark_price_twap(clock.unix_timestamp)?;
+
+    let old_mark_price = market.get_mark_price()?;
+    let old_peg_multiplier = market.peg_multiplier;
+    market.peg_multiplier = new_peg_multiplier;
+    market.record_mark_price()?;
+    let new_mark_price = market.last_mark_price;
+    require!(
+        new_mark_price >= old_mark_price.min(oracle_price)
+            && new_mark_price <= old_mark_price.max(oracle_price),
+        PerpError::InvalidRepeg
+    );
+
+    let cost = calculate_repeg_cost(
+        market.amm_base_asset_reserve,
+        market.amm_quote_asset_reserve,
+        market.get_net_user_base_asset_amount()?,
+        old_peg_multiplier,
+        new_peg_multiplier,
+    )?;
+    market.settle_amm_cost(cost)?;
+
+    Ok(())
+}
+
+/// Scales both vAMM reserves by `scale_factor`, deepening (above 1) or thinning (below 1) the
+/// market's liquidity while keeping its mark price. The cost to the vAMM, given the users' net
+/// position against it, is charged to the fee pool, which must be able to cover it.
+pub fn handle_update_k(ctx: Context<AdminUpdateMarket>, scale_factor: u64) -> Result<()> {
+    require_gt!(scale_factor, 0, PerpError::InvalidAmount);
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    market.update_mark_price_twap(Clock::get()?.unix_timestamp)?;
+    market.scale_reserves(scale_factor)
+}
@@ -0,0 +1,137 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{MARKET_SEED, PRECISION};
+use crate::state::market::Market;
+use crate::state::user::User;
+use crate::error::PerpError;
+use crate::instructions::trade::reduce_position_against_amm;
+use crate::math::bankruptcy::calculate_adl_score;
+use crate::math::margin::{collateral_to_quote, quote_to_collateral};
+use crate::validation::{validate_oracle_price, validate_user_not_locked};
+
+/// Context for recovering a market's pending ADL deficit from profitable counterparties.
+/// The `User` accounts of the counterparties, all on the side being deleveraged, are passed as
+/// writable remaining accounts in descending ADL score order.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct AutoDeleverage<'info> {
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
+}
+
+/// Force-reduces profitable counterparties against the vAMM in the order given and claws back
+/// their realized profit until the pending deficit on their side is recovered. Each one's funding
+/// and socialized loss are settled first. Scores must not increase along the list, and each must
+/// be at least the score of an unleveraged position at the side's average entry price, so
+/// positions below the side's average are never deleveraged ahead of it.
+pub fn handle_auto_deleverage(ctx: Context<AutoDeleverage>, market_index: u16) -> Result<()> {
+    let mut market = ctx.accounts.market.load_mut()?;
+    require_keys_eq!(
+        market.oracle_price_feed,
+        ctx.accounts.oracle_price_feed.key(),
+        PerpError::InvalidOraclePrice
+    );
+    let clock = Clock::get()?;
+    let oracle_price = validate_oracle_price(&ctx.accounts.oracle_price_feed, &clock)?;
+    market.update_oracle_price_twap(oracle_price, clock.unix_timestamp)?;
+
+    // The side and its score bound are fixed by the first counterparty, before any reduction
+    let mut side: Option<(bool, u128)> = None;
+    let mut previous_score = u128::MAX;
+    for (index, user_info) in ctx.remaining_accounts.iter().enumerate() {
+        require!(
+            ctx.remaining_accounts[..index]
+                .iter()
+                .all(|a| a.key != user_info.key),
+            PerpError::InvalidAdlCounterparty
+        );
+        let user_loader: AccountLoader<User> = AccountLoader::try_from(user_info)?;
+        let mut user = user_loader.load_mut()?;
+        validate_user_not_locked(&user)?;
+        user.settle_funding_payment(&mut market)?;
+        user.settle_socialized_loss(&mut market)?;
+
+        let position = *user.find_position_mut(market_index)?;
+        let position_is_long = position.base_asset_amount > 0;
+        let (is_long, score_bound) = match side {
+            Some(side) => side,
+            None => *side.insert((
+                position_is_long,
+                market.get_adl_score_bound(oracle_price, position_is_long)?,
+            )),
+        };
+        require!(
+            position.base_asset_amount != 0 && position_is_long == is_long,
+            PerpError::InvalidAdlCounterparty
+        );
+
+        let pending_adl_deficit = if is_long {
+            market.pending_adl_deficit_long
+        } else {
+            market.pending_adl_deficit_short
+        };
+        if index == 0 {
+            require_gt!(pending_adl_deficit, 0, PerpError::InvalidAdlCounterparty);
+        } else if pending_adl_deficit == 0 {
+            break;
+        }
+
+        let unrealized_pnl = position.get_unrealized_pnl(oracle_price)?;
+        require_gt!(unrealized_pnl, 0, PerpError::InvalidAdlCounterparty);
+        let unrealized_pnl = unrealized_pnl.unsigned_abs();
+
+        let position_size = position.base_asset_amount.unsigned_abs();
+        let notional = position_size
+            .checked_mul(oracle_price)
+            .and_then(|n| n.checked_div(PRECISION))
+            .ok_or(PerpError::MathOverflow)?;
+        let score = calculate_adl_score(
+            unrealized_pnl,
+            position.quote_asset_amount,
+            notional,
+            collateral_to_quote(user.collateral)?,
+        )?;
+        require_gte!(previous_score, score, PerpError::AdlCandidatesOutOfOrder);
+        require_gte!(score, score_bound, PerpError::AdlScoreBelowSide);
+        previous_score = score;
+
+        // Close just enough of the position for its profit to cover the pending deficit
+        let pending_adl_deficit_quote = collateral_to_quote(pending_adl_deficit)?;
+        let base_asset_amount_to_close = if pending_adl_deficit_quote >= unrealized_pnl {
+            position_size
+        } else {
+            position_size
+                .checked_mul(pending_adl_deficit_quote)
+                .and_then(|b| b.checked_add(unrealized_pnl - 1))
+                .and_then(|b| b.checked_div(unrealized_pnl))
+                .ok_or(PerpError::MathOverflow)?
+        };
+
+        let (_, realized_pnl) =
+            reduce_position_against_amm(&mut user, &mut market, base_asset_amount_to_close)?;
+        let shortfall = user.settle_realized_pnl(realized_pnl)?;
+        require!(shortfall == 0, PerpError::InsufficientCollateral);
+
+        let clawback = quote_to_collateral(realized_pnl.max(0) as u128)?
+            .min(pending_adl_deficit)
+            .min(user.collateral);
+        user.collateral -= clawback;
+
+        let pending_adl_deficit = if is_long {
+            &mut market.pending_adl_deficit_long
+        } else {
+            &mut market.pending_adl_deficit_short
+        };
+        *pending_adl_deficit -= clawback;
+    }
+
+    Ok(())
+}
@@ -1,9 +1,15 @@
 use anchor_lang::prelude::*;
 use pyth_sdk_solana::PriceFeed;
-use crate::state::constants::{FUNDING_PERIOD, MARKET_SEED};
+use crate::state::constants::{
+    DEFAULT_INSURANCE_FEE_SHARE, DEFAULT_LIQUIDATION_MARGIN_BUFFER_RATIO,
+    DEFAULT_LIQUIDATION_TRANSFER_DISCOUNT, DEFAULT_LIQUIDATOR_FEE_SHARE, DEFAULT_MAX_FUNDING_RATE,
+    DEFAULT_MAX_MARK_ORACLE_DIVERGENCE_BPS, DEFAULT_MIN_ORDER_SIZE, FUNDING_PERIOD, MARKET_SEED,
+    PEG_PRECISION,
+};
 use crate::state::market::Market;
 use crate::state::state::State;
 use crate::error::PerpError;
+use crate::validation::validate_oracle_price;
 
 /// Context for creating a new perpetual market.
 #[derive(Accounts)]
@@ -58,6 +64,8 @@ pub fn handle(
     let price_feed: PriceFeed = pyth_sdk_solana::load_price_feed_from_account_info(price_feed_info)
         .map_err(|_| error!(PerpError::InvalidOraclePrice))?;
     let _current_price = price_feed.get_price().ok_or(PerpError::InvalidOraclePrice)?;
+    let clock = Clock::get()?;
+    let oracle_price = validate_oracle_price(price_feed_info, &clock)?;
 
     // Initialize market
     let mut market = ctx.accounts.market.load_init()?;
@@ -80,9 +88,11 @@ pub fn handle(
     market.initial_margin_ratio = initial_margin_ratio;
     market.maintenance_margin_ratio = maintenance_margin_ratio;
 
-    market.last_funding_ts = Clock::get()?.unix_timestamp;
+    market.last_funding_ts = clock.unix_timestamp;
     market.funding_period = FUNDING_PERIOD;
 
+    initialize_market_defaults(&mut market, oracle_price, clock.unix_timestamp)?;
+
     // Increment global market count
     ctx.accounts.program_state.number_of_markets = ctx
         .accounts
@@ -93,3 +103,26 @@ pub fn handle(
 
     Ok(())
 }
+
+/// Sets the fields `create_market` does not take as arguments to their defaults and starts the
+/// price TWAPs at the current mark and `oracle_price`. Also used to fill in the fields of a market
+/// migrated from the original layout.
+pub fn initialize_market_defaults(market: &mut Market, oracle_price: u128, now: i64) -> Result<()> {
+    market.peg_multiplier = PEG_PRECISION;
+    market.max_mark_oracle_divergence_bps = DEFAULT_MAX_MARK_ORACLE_DIVERGENCE_BPS;
+    market.min_order_size = DEFAULT_MIN_ORDER_SIZE;
+
+    market.insurance_fee_share = DEFAULT_INSURANCE_FEE_SHARE;
+    market.liquidator_fee_share = DEFAULT_LIQUIDATOR_FEE_SHARE;
+    market.liquidation_transfer_discount = DEFAULT_LIQUIDATION_TRANSFER_DISCOUNT;
+    market.liquidation_margin_buffer_ratio = DEFAULT_LIQUIDATION_MARGIN_BUFFER_RATIO;
+
+    market.max_funding_rate = DEFAULT_MAX_FUNDING_RATE;
+    market.record_mark_price()?;
+    market.last_mark_price_twap = market.last_mark_price;
+    market.last_mark_price_twap_ts = now;
+    market.last_oracle_price_twap = oracle_price;
+    market.last_oracle_price_twap_ts = now;
+
+    Ok(())
+}
@@ -0,0 +1,327 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+
+use crate::state::constants::{
+    INSURANCE_FUND_STAKE_SEED, INSURANCE_VAULT_SEED, MARKET_SEED, PROGRAM_SEED, VAULT_SEED,
+};
+use crate::state::market::Market;
+use crate::state::state::State;
+use crate::state::user::InsuranceFundStake;
+use crate::error::PerpError;
+use crate::math::insurance::{amount_to_shares, shares_to_amount};
+
+/// Creates the insurance fund vault. Only callable by the program admin.
+#[derive(Accounts)]
+pub struct InitializeInsuranceFund<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = admin,
+    )]
+    pub program_state: Account<'info, State>,
+
+    #[account(address = program_state.usdc_mint)]
+    pub usdc_mint: Account<'info, Mint>,
+
+    /// The vault holding staked and collected insurance funds (PDA).
+    #[account(
+        init,
+        payer = admin,
+        seeds = [INSURANCE_VAULT_SEED, usdc_mint.key().as_ref()],
+        bump,
+        token::mint = usdc_mint,
+        token::authority = program_state,
+    )]
+    pub insurance_vault: Account<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+pub fn handle_initialize_insurance_fund(
+    ctx: Context<InitializeInsuranceFund>,
+    unstaking_period: i64,
+) -> Result<()> {
+    require_gte!(unstaking_period, 0, PerpError::InvalidAmount);
+
+    let state = &mut ctx.accounts.program_state;
+    state.insurance_fund.total_shares = 0;
+    state.insurance_fund.unstaking_period = unstaking_period;
+
+    Ok(())
+}
+
+/// Creates a staker's insurance fund stake account.
+#[derive(Accounts)]
+pub struct InitializeInsuranceFundStake<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = InsuranceFundStake::LEN,
+        seeds = [INSURANCE_FUND_STAKE_SEED, authority.key().as_ref()],
+        bump
+    )]
+    pub insurance_fund_stake: Account<'info, InsuranceFundStake>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handle_initialize_insurance_fund_stake(
+    ctx: Context<InitializeInsuranceFundStake>,
+) -> Result<()> {
+    let stake = &mut ctx.accounts.insurance_fund_stake;
+    stake.authority = *ctx.accounts.authority.key;
+    stake.bump = ctx.bumps.insurance_fund_stake;
+    Ok(())
+}
+
+/// Staker deposits collateral into the insurance fund in exchange for shares.
+#[derive(Accounts)]
+pub struct AddInsuranceFundStake<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [INSURANCE_FUND_STAKE_SEED, authority.key().as_ref()],
+        bump = insurance_fund_stake.bump
+    )]
+    pub insurance_fund_stake: Account<'info, InsuranceFundStake>,
+
+    #[account(mut)]
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        mut,
+        seeds = [INSURANCE_VAULT_SEED, program_state.usdc_mint.key().as_ref()],
+        bump
+    )]
+    pub insurance_vault: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_collateral_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn handle_add_insurance_fund_stake(
+    ctx: Context<AddInsuranceFundStake>,
+    amount: u64,
+) -> Result<()> {
+    require_gt!(amount, 0, PerpError::InvalidAmount);
+
+    // Price the new shares against the vault before the deposit lands
+    let insurance_fund = &mut ctx.accounts.program_state.insurance_fund;
+    insurance_fund.rebase_if_depleted(ctx.accounts.insurance_vault.amount)?;
+    insurance_fund.seed_protocol_shares(ctx.accounts.insurance_vault.amount);
+    let shares = amount_to_shares(
+        amount,
+        insurance_fund.total_shares,
+        ctx.accounts.insurance_vault.amount,
+    )?;
+    insurance_fund.total_shares = insurance_fund
+        .total_shares
+        .checked_add(shares)
+        .ok_or(PerpError::MathOverflow)?;
+
+    let stake = &mut ctx.accounts.insurance_fund_stake;
+    stake.sync_shares_epoch(insurance_fund.shares_epoch);
+    stake.shares = stake.shares.checked_add(shares).ok_or(PerpError::MathOverflow)?;
+
+    let cpi_accounts = Transfer {
+        from: ctx.accounts.user_collateral_account.to_account_info(),
+        to: ctx.accounts.insurance_vault.to_account_info(),
+        authority: ctx.accounts.authority.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+    token::transfer(cpi_ctx, amount)?;
+
+    Ok(())
+}
+
+/// Staker requests to remove shares, starting the unstaking cooldown.
+#[derive(Accounts)]
+pub struct RequestRemoveInsuranceFundStake<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [INSURANCE_FUND_STAKE_SEED, authority.key().as_ref()],
+        bump = insurance_fund_stake.bump
+    )]
+    pub insurance_fund_stake: Account<'info, InsuranceFundStake>,
+
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        seeds = [INSURANCE_VAULT_SEED, program_state.usdc_mint.key().as_ref()],
+        bump
+    )]
+    pub insurance_vault: Account<'info, TokenAccount>,
+}
+
+pub fn handle_request_remove_insurance_fund_stake(
+    ctx: Context<RequestRemoveInsuranceFundStake>,
+    shares: u128,
+) -> Result<()> {
+    let stake = &mut ctx.accounts.insurance_fund_stake;
+    stake.sync_shares_epoch(ctx.accounts.program_state.insurance_fund.shares_epoch);
+    require_gt!(shares, 0, PerpError::InvalidAmount);
+    require_gte!(stake.shares, shares, PerpError::InvalidAmount);
+    require!(
+        stake.last_withdraw_request_shares == 0,
+        PerpError::WithdrawRequestPending
+    );
+
+    stake.last_withdraw_request_shares = shares;
+    stake.last_withdraw_request_value = shares_to_amount(
+        shares,
+        ctx.accounts.program_state.insurance_fund.total_shares,
+        ctx.accounts.insurance_vault.amount,
+    )?;
+    stake.last_withdraw_request_ts = Clock::get()?.unix_timestamp;
+
+    Ok(())
+}
+
+/// Staker completes a removal request once the unstaking period has elapsed.
+#[derive(Accounts)]
+pub struct RemoveInsuranceFundStake<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [INSURANCE_FUND_STAKE_SEED, authority.key().as_ref()],
+        bump = insurance_fund_stake.bump
+    )]
+    pub insurance_fund_stake: Account<'info, InsuranceFundStake>,
+
+    #[account(mut)]
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        mut,
+        seeds = [INSURANCE_VAULT_SEED, program_state.usdc_mint.key().as_ref()],
+        bump
+    )]
+    pub insurance_vault: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_collateral_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn handle_remove_insurance_fund_stake(ctx: Context<RemoveInsuranceFundStake>) -> Result<()> {
+    let stake = &mut ctx.accounts.insurance_fund_stake;
+    let insurance_fund = &mut ctx.accounts.program_state.insurance_fund;
+    stake.sync_shares_epoch(insurance_fund.shares_epoch);
+    let shares = stake.last_withdraw_request_shares;
+    require_gt!(shares, 0, PerpError::NoWithdrawRequest);
+
+    let unstake_ts = stake
+        .last_withdraw_request_ts
+        .checked_add(insurance_fund.unstaking_period)
+        .ok_or(PerpError::MathOverflow)?;
+    require_gte!(
+        Clock::get()?.unix_timestamp,
+        unstake_ts,
+        PerpError::UnstakingPeriodNotElapsed
+    );
+
+    // Stakers keep losses incurred during the cooldown but forfeit gains
+    let current_value = shares_to_amount(
+        shares,
+        insurance_fund.total_shares,
+        ctx.accounts.insurance_vault.amount,
+    )?;
+    let amount = current_value.min(stake.last_withdraw_request_value);
+
+    stake.shares = stake.shares.checked_sub(shares).ok_or(PerpError::MathOverflow)?;
+    stake.last_withdraw_request_shares = 0;
+    stake.last_withdraw_request_value = 0;
+    stake.last_withdraw_request_ts = 0;
+    insurance_fund.total_shares = insurance_fund
+        .total_shares
+        .checked_sub(shares)
+        .ok_or(PerpError::MathOverflow)?;
+
+    // Transfer funds using signer seeds
+    let state_bump = ctx.accounts.program_state.bump;
+    let signer_seeds = &[&PROGRAM_SEED[..], &[state_bump]];
+    let signer = &[&signer_seeds[..]];
+
+    let cpi_accounts = Transfer {
+        from: ctx.accounts.insurance_vault.to_account_info(),
+        to: ctx.accounts.user_collateral_account.to_account_info(),
+        authority: ctx.accounts.program_state.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer);
+    token::transfer(cpi_ctx, amount)?;
+
+    Ok(())
+}
+
+/// Moves a market's accrued insurance fees from the collateral vault into the insurance fund.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct SettleInsuranceFeePool<'info> {
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, program_state.usdc_mint.key().as_ref()],
+        bump
+    )]
+    pub collateral_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [INSURANCE_VAULT_SEED, program_state.usdc_mint.key().as_ref()],
+        bump
+    )]
+    pub insurance_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn handle_settle_insurance_fee_pool(
+    ctx: Context<SettleInsuranceFeePool>,
+    _market_index: u16,
+) -> Result<()> {
+    let mut market = ctx.accounts.market.load_mut()?;
+    let amount = market.insurance_fee_pool;
+    if amount == 0 {
+        return Ok(());
+    }
+    market.insurance_fee_pool = 0;
+
+    let state_bump = ctx.accounts.program_state.bump;
+    let signer_seeds = &[&PROGRAM_SEED[..], &[state_bump]];
+    let signer = &[&signer_seeds[..]];
+
+    let cpi_accounts = Transfer {
+        from: ctx.accounts.collateral_vault.to_account_info(),
+        to: ctx.accounts.insurance_vault.to_account_info(),
+        authority: ctx.accounts.program_state.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer);
+    token::transfer(cpi_ctx, amount)?;
+
+    Ok(())
+}
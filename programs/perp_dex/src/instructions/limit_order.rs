@@ -0,0 +1,298 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{MARKET_SEED, PRECISION, USER_SEED};
+use crate::state::market::Market;
+use crate::state::user::{LimitOrder, User};
+use crate::error::PerpError;
+use crate::instructions::order_book::release_open_orders;
+use crate::instructions::trade::{open_position_against_amm, read_oracle_price};
+use crate::math::amm;
+use crate::math::fees::calculate_filler_reward;
+use crate::math::margin::meets_initial_margin_requirement;
+use crate::validation::{
+    is_expired, validate_mark_oracle_divergence, validate_market_not_paused, validate_max_ts,
+    validate_user_not_locked,
+};
+
+/// Context for a user placing a resting limit order.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct PlaceLimitOrder<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [USER_SEED, authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+}
+
+/// Stores a limit order for `base_asset_amount` (positive buys, negative sells) at `price` in the
+/// user's first free limit order slot. The order locks margin like a resting book order.
+pub fn handle_place_limit_order(
+    ctx: Context<PlaceLimitOrder>,
+    market_index: u16,
+    base_asset_amount: i128,
+    price: u128,
+    max_ts: Option<i64>,
+) -> Result<()> {
+    require!(base_asset_amount != 0, PerpError::InvalidAmount);
+    require_gt!(price, 0, PerpError::InvalidAmount);
+
+    let max_ts = validate_max_ts(max_ts, Clock::get()?.unix_timestamp)?;
+
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let market = ctx.accounts.market.load()?;
+
+    validate_user_not_locked(&user)?;
+    validate_market_not_paused(&market)?;
+
+    let order_id = user.next_limit_order_id;
+    let is_long = base_asset_amount > 0;
+    let limit_order = user
+        .limit_orders
+        .iter_mut()
+        .find(|o| !o.is_active)
+        .ok_or(PerpError::LimitOrdersFull)?;
+    *limit_order = LimitOrder {
+        price,
+        base_asset_amount: base_asset_amount.unsigned_abs(),
+        order_id,
+        max_ts,
+        market_index,
+        is_long,
+        is_active: true,
+        _padding: [0; 12],
+    };
+    user.next_limit_order_id = order_id.checked_add(1).ok_or(PerpError::MathOverflow)?;
+
+    let position = user.find_or_create_position_mut(market_index)?;
+    let open_orders = if is_long {
+        &mut position.open_bids
+    } else {
+        &mut position.open_asks
+    };
+    *open_orders = open_orders
+        .checked_add(base_asset_amount.unsigned_abs())
+        .ok_or(PerpError::MathOverflow)?;
+
+    // Release the market borrow so the margin engine can load it from the remaining accounts.
+    drop(market);
+
+    require!(
+        meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+
+    Ok(())
+}
+
+/// Context for a user modifying or cancelling their own limit orders.
+#[derive(Accounts)]
+pub struct UpdateLimitOrders<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [USER_SEED, authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, User>,
+}
+
+/// Changes the unfilled size and price of limit order `order_id`, keeping its side.
+pub fn handle_modify_limit_order(
+    ctx: Context<UpdateLimitOrders>,
+    order_id: u64,
+    base_asset_amount: u128,
+    price: u128,
+) -> Result<()> {
+    require_gt!(base_asset_amount, 0, PerpError::InvalidAmount);
+    require_gt!(price, 0, PerpError::InvalidAmount);
+
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    validate_user_not_locked(&user)?;
+
+    let order_index = user.find_limit_order_index(order_id)?;
+    let order = user.limit_orders[order_index];
+
+    let position = user.find_position_mut(order.market_index)?;
+    let open_orders = if order.is_long {
+        &mut position.open_bids
+    } else {
+        &mut position.open_asks
+    };
+    *open_orders = open_orders
+        .checked_sub(order.base_asset_amount)
+        .and_then(|o| o.checked_add(base_asset_amount))
+        .ok_or(PerpError::MathOverflow)?;
+
+    let limit_order = &mut user.limit_orders[order_index];
+    limit_order.base_asset_amount = base_asset_amount;
+    limit_order.price = price;
+
+    require!(
+        meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+
+    Ok(())
+}
+
+/// Cancels limit order `order_id`, releasing the margin it locked.
+pub fn handle_cancel_limit_order(ctx: Context<UpdateLimitOrders>, order_id: u64) -> Result<()> {
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    validate_user_not_locked(&user)?;
+
+    let order_index = user.find_limit_order_index(order_id)?;
+    let order = user.limit_orders[order_index];
+    user.limit_orders[order_index] = LimitOrder::default();
+
+    release_open_orders(&mut user, order.market_index, order.is_long, order.base_asset_amount)
+}
+
+/// Cancels all of the user's limit orders, releasing the margin they locked.
+pub fn cancel_all_limit_orders(user: &mut User) -> Result<()> {
+    for order_index in 0..user.limit_orders.len() {
+        let order = user.limit_orders[order_index];
+        if !order.is_active {
+            continue;
+        }
+        user.limit_orders[order_index] = LimitOrder::default();
+        release_open_orders(user, order.market_index, order.is_long, order.base_asset_amount)?;
+    }
+
+    Ok(())
+}
+
+/// Context for a keeper filling a user's limit order against the vAMM.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct FillLimitOrder<'info> {
+    pub keeper: Signer<'info>,
+
+    /// The keeper's own user account, credited with the filler reward.
+    #[account(
+        mut,
+        seeds = [USER_SEED, keeper.key().as_ref()],
+        bump = keeper_account.load()?.bump,
+        constraint = keeper_account.key() != user_account.key() @ PerpError::CannotMatchOwnOrder
+    )]
+    pub keeper_account: AccountLoader<'info, User>,
+
+    #[account(mut)]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
+}
+
+/// Fills as much of limit order `order_id` against the vAMM as its average price allows within
+/// the limit; the rest keeps resting. A partial fill must be at least the market's minimum order
+/// size. The order's owner pays the keeper `LIMIT_ORDER_FILLER_REWARD_RATE` of the fill's notional
+/// at the limit price and must meet the initial margin requirement afterwards. Expired orders are
+/// cancelled instead.
+pub fn handle_fill_limit_order(
+    ctx: Context<FillLimitOrder>,
+    market_index: u16,
+    order_id: u64,
+) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let mut market = ctx.accounts.market.load_mut()?;
+
+    validate_user_not_locked(&user)?;
+    validate_market_not_paused(&market)?;
+
+    let order_index = user.find_limit_order_index(order_id)?;
+    let order = user.limit_orders[order_index];
+    require_eq!(order.market_index, market_index, PerpError::InvalidMarketIndex);
+
+    if is_expired(order.max_ts, now) {
+        user.limit_orders[order_index] = LimitOrder::default();
+        return release_open_orders(&mut user, market_index, order.is_long, order.base_asset_amount);
+    }
+
+    // The fill is sized against the spreads, which depend on the oracle confidence and the
+    // mark TWAP, so both are brought up to date first
+    let oracle_price = read_oracle_price(&mut market, &ctx.accounts.oracle_price_feed)?;
+    market.update_mark_price_twap(now)?;
+    let mark_price_before = market.get_mark_price()?;
+
+    let direction = if order.is_long {
+        amm::TradeDirection::Long
+    } else {
+        amm::TradeDirection::Short
+    };
+    let base_asset_amount = order
+        .base_asset_amount
+        .min(market.get_max_base_asset_amount_within_limit(order.price, direction)?);
+    require_gt!(base_asset_amount, 0, PerpError::LimitPriceNotCrossed);
+    if base_asset_amount < order.base_asset_amount {
+        require_gte!(base_asset_amount, market.min_order_size, PerpError::OrderTooSmall);
+    }
+
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
+
+    // The filled part stops locking margin as an open order and becomes position
+    release_open_orders(&mut user, market_index, order.is_long, base_asset_amount)?;
+    let base_asset_amount_delta = if order.is_long {
+        base_asset_amount as i128
+    } else {
+        -(base_asset_amount as i128)
+    };
+    open_position_against_amm(&mut user, &mut market, base_asset_amount_delta, order.price)?;
+    validate_mark_oracle_divergence(&market, oracle_price, mark_price_before)?;
+
+    let limit_order = &mut user.limit_orders[order_index];
+    limit_order.base_asset_amount = limit_order
+        .base_asset_amount
+        .checked_sub(base_asset_amount)
+        .ok_or(PerpError::MathOverflow)?;
+    if limit_order.base_asset_amount == 0 {
+        *limit_order = LimitOrder::default();
+    }
+
+    let filler_reward = calculate_filler_reward(
+        base_asset_amount
+            .checked_mul(order.price)
+            .and_then(|q| q.checked_div(PRECISION))
+            .ok_or(PerpError::MathOverflow)?,
+    )?;
+    user.collateral = user
+        .collateral
+        .checked_sub(filler_reward)
+        .ok_or(PerpError::InsufficientCollateral)?;
+    let mut keeper_account = ctx.accounts.keeper_account.load_mut()?;
+    keeper_account.collateral = keeper_account
+        .collateral
+        .checked_add(filler_reward)
+        .ok_or(PerpError::MathOverflow)?;
+    drop(keeper_account);
+
+    // Release the market borrow so the margin engine can load it from the remaining accounts.
+    drop(market);
+
+    require!(
+        meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+
+    Ok(())
+}
@@ -1,9 +1,21 @@
 use anchor_lang::prelude::*;
-use crate::state::constants::{MARKET_SEED, USER_SEED};
-use crate::state::market::Market;
+use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+use crate::state::constants::{
+    INSURANCE_VAULT_SEED, MARKET_SEED, ORDER_BOOK_SEED, PRECISION, PROGRAM_SEED, RATIO_PRECISION,
+    USER_SEED, VAULT_SEED,
+};
+use crate::state::market::{Market, OrderBook};
+use crate::state::state::State;
 use crate::state::user::User;
 use crate::error::PerpError;
-use crate::math::margin::is_liquidatable;
+use crate::instructions::limit_order::cancel_all_limit_orders;
+use crate::instructions::order_book::cancel_user_orders;
+use crate::instructions::trade::{reduce_position_against_amm, update_position_with_fill};
+use crate::math::fees::{calculate_liquidation_fee, split_liquidation_fee};
+use crate::math::liquidation::calculate_base_asset_amount_to_liquidate;
+use crate::math::margin::{
+    is_liquidatable, load_market_oracle_map, quote_to_collateral, MarginRequirementType,
+};
 use crate::validation::{validate_user_not_locked, validate_market_not_paused};
 
 #[derive(Accounts)]
@@ -11,6 +23,16 @@ use crate::validation::{validate_user_not_locked, validate_market_not_paused};
 pub struct Liquidate<'info> {
     pub liquidator: Signer<'info>,
 
+    /// The liquidator's own user account, credited with their share of the fee
+    /// or taking over the position in a transfer liquidation.
+    #[account(
+        mut,
+        seeds = [USER_SEED, liquidator.key().as_ref()],
+        bump = liquidator_account.load()?.bump,
+        constraint = liquidator_account.key() != user_account.key() @ PerpError::CannotLiquidateSelf
+    )]
+    pub liquidator_account: AccountLoader<'info, User>,
+
     #[account(
         mut,
         seeds = [USER_SEED, user_account.load()?.authority.as_ref()],
@@ -24,11 +46,39 @@ pub struct Liquidate<'info> {
         bump = market.load()?.bump
     )]
     pub market: AccountLoader<'info, Market>,
+
+    /// The market's order book, required if the user has resting orders on it.
+    #[account(
+        mut,
+        seeds = [ORDER_BOOK_SEED, &market_index.to_le_bytes()],
+        bump
+    )]
+    pub order_book: Option<AccountLoader<'info, OrderBook>>,
+
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, program_state.usdc_mint.key().as_ref()],
+        bump
+    )]
+    pub collateral_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [INSURANCE_VAULT_SEED, program_state.usdc_mint.key().as_ref()],
+        bump
+    )]
+    pub insurance_vault: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
 }
 
 pub fn handle_liquidate(ctx: Context<Liquidate>, market_index: u16) -> Result<()> {
+    let clock = Clock::get()?;
+    let market_oracle_map = load_market_oracle_map(&ctx.remaining_accounts, &clock)?;
     let mut user = ctx.accounts.user_account.load_mut()?;
-    let market = ctx.accounts.market.load()?;
+    let mut market = ctx.accounts.market.load_mut()?;
 
     validate_user_not_locked(&user)?;
     validate_market_not_paused(&market)?;
@@ -36,33 +86,316 @@ pub fn handle_liquidate(ctx: Context<Liquidate>, market_index: u16) -> Result<()
     user.operation_lock = true;
 
     require!(
-        is_liquidatable(&user, &market, &ctx.remaining_accounts)?,
+        is_liquidatable(&user, &market_oracle_map)?,
         PerpError::PositionNotLiquidatable
     );
 
-    let position_to_liquidate = user.find_position_mut(market_index)?.clone();
+    let user_key = ctx.accounts.user_account.key();
+    cancel_open_orders(&mut user, user_key, market_index, ctx.accounts.order_book.as_ref())?;
 
-    let position_value = position_to_liquidate
-        .base_asset_amount
-        .abs()
-        .checked_mul(market.get_mark_price()?)
-        .ok_or(PerpError::MathOverflow)?;
+    let oracle_price = market_oracle_map
+        .get(&market_index)
+        .ok_or(PerpError::MarketAccountMissing)?
+        .oracle_price;
+    market.update_oracle_price_twap(oracle_price, clock.unix_timestamp)?;
+
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
 
-    let liquidation_fee = (position_value as u64)
-        .checked_mul(market.liquidation_fee_rate)
-        .and_then(|f| f.checked_div(1_000_000))
+    // Close only enough base to restore the target margin buffer, within the per-slot limit
+    let margin_shortage = user
+        .free_collateral(&market_oracle_map, MarginRequirementType::LiquidationTarget)?
+        .min(0)
+        .unsigned_abs();
+    let target_margin_ratio = market
+        .maintenance_margin_ratio
+        .checked_add(market.liquidation_margin_buffer_ratio)
         .ok_or(PerpError::MathOverflow)?;
+    let position = user.find_position_mut(market_index)?;
+    let was_long = position.base_asset_amount > 0;
+    let base_asset_amount_to_liquidate = calculate_base_asset_amount_to_liquidate(
+        margin_shortage,
+        oracle_price,
+        target_margin_ratio,
+        market.liquidation_fee_rate,
+    )?
+    .min(position.base_asset_amount.unsigned_abs())
+    .min(market.get_liquidation_capacity(clock.slot));
+    require_gt!(base_asset_amount_to_liquidate, 0, PerpError::LiquidationLimitReached);
+    market.record_liquidation(clock.slot, base_asset_amount_to_liquidate)?;
+
+    let (mut quote_asset_amount, mut pnl) =
+        reduce_position_against_amm(&mut user, &mut market, base_asset_amount_to_liquidate)?;
+
+    // A loss the collateral cannot cover leaves the account bankrupt, so the rest of the
+    // position is closed as well, beyond the per-slot limit
+    let remaining_base_asset_amount = user
+        .find_position_mut(market_index)
+        .map(|p| p.base_asset_amount.unsigned_abs())
+        .unwrap_or(0);
+    if remaining_base_asset_amount > 0
+        && pnl < 0
+        && quote_to_collateral(pnl.unsigned_abs())? > user.collateral
+    {
+        market.record_liquidation(clock.slot, remaining_base_asset_amount)?;
+        let (remaining_quote_asset_amount, remaining_pnl) =
+            reduce_position_against_amm(&mut user, &mut market, remaining_base_asset_amount)?;
+        quote_asset_amount = quote_asset_amount
+            .checked_add(remaining_quote_asset_amount)
+            .ok_or(PerpError::MathOverflow)?;
+        pnl = pnl.checked_add(remaining_pnl).ok_or(PerpError::MathOverflow)?;
+    }
+    let deficit = user.settle_realized_pnl(pnl)?;
 
-    user.collateral = user
+    // The fee is paid out of whatever collateral remains and split between the liquidator
+    // and the insurance fund; an unpaid loss is a deficit the insurance fund has to cover
+    let liquidation_fee = calculate_liquidation_fee(quote_asset_amount, market.liquidation_fee_rate)?
+        .min(user.collateral);
+    user.collateral -= liquidation_fee;
+    let (liquidator_fee, insurance_fee) =
+        split_liquidation_fee(liquidation_fee, market.liquidator_fee_share)?;
+
+    let mut liquidator_account = ctx.accounts.liquidator_account.load_mut()?;
+    liquidator_account.collateral = liquidator_account
         .collateral
-        .checked_sub(liquidation_fee)
-        .ok_or(PerpError::InsufficientCollateral)?;
+        .checked_add(liquidator_fee)
+        .ok_or(PerpError::MathOverflow)?;
+    drop(liquidator_account);
+
+    let insurance_draw = resolve_deficit(
+        &mut market,
+        deficit,
+        was_long,
+        ctx.accounts.insurance_vault.amount,
+    )?;
+
+    user.operation_lock = false;
+    drop(user);
+    drop(market);
+
+    transfer_between_vaults(
+        &ctx.accounts.token_program,
+        &ctx.accounts.program_state,
+        &ctx.accounts.collateral_vault,
+        &ctx.accounts.insurance_vault,
+        insurance_fee,
+    )?;
+    transfer_between_vaults(
+        &ctx.accounts.token_program,
+        &ctx.accounts.program_state,
+        &ctx.accounts.insurance_vault,
+        &ctx.accounts.collateral_vault,
+        insurance_draw,
+    )?;
+
+    Ok(())
+}
+
+/// Liquidates by transferring up to `max_base_asset_amount` of the position onto the liquidator's
+/// user account at a discount to the oracle price instead of closing it against the vAMM.
+/// If the transfer leaves the account bankrupt, the rest of the position is closed against the
+/// vAMM. The liquidator must meet the initial margin requirement afterwards.
+pub fn handle_liquidate_perp_transfer(
+    ctx: Context<Liquidate>,
+    market_index: u16,
+    max_base_asset_amount: u128,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let market_oracle_map = load_market_oracle_map(&ctx.remaining_accounts, &clock)?;
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let mut liquidator_account = ctx.accounts.liquidator_account.load_mut()?;
+    let mut market = ctx.accounts.market.load_mut()?;
+
+    validate_user_not_locked(&user)?;
+    validate_user_not_locked(&liquidator_account)?;
+    validate_market_not_paused(&market)?;
+
+    user.operation_lock = true;
+
+    require!(
+        is_liquidatable(&user, &market_oracle_map)?,
+        PerpError::PositionNotLiquidatable
+    );
+
+    let user_key = ctx.accounts.user_account.key();
+    cancel_open_orders(&mut user, user_key, market_index, ctx.accounts.order_book.as_ref())?;
 
+    let oracle_price = market_oracle_map
+        .get(&market_index)
+        .ok_or(PerpError::MarketAccountMissing)?
+        .oracle_price;
+    market.update_oracle_price_twap(oracle_price, clock.unix_timestamp)?;
+
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
+    liquidator_account.settle_funding_payment(&mut market)?;
+    liquidator_account.settle_socialized_loss(&mut market)?;
+
+    // Transfer only enough base to restore the target margin buffer
+    let margin_shortage = user
+        .free_collateral(&market_oracle_map, MarginRequirementType::LiquidationTarget)?
+        .min(0)
+        .unsigned_abs();
+    let target_margin_ratio = market
+        .maintenance_margin_ratio
+        .checked_add(market.liquidation_margin_buffer_ratio)
+        .ok_or(PerpError::MathOverflow)?;
     let position = user.find_position_mut(market_index)?;
-    *position = Default::default();
-    position.market_index = market_index;
+    let was_long = position.base_asset_amount > 0;
+    let base_asset_amount_to_transfer = calculate_base_asset_amount_to_liquidate(
+        margin_shortage,
+        oracle_price,
+        target_margin_ratio,
+        market.liquidation_transfer_discount,
+    )?
+    .min(position.base_asset_amount.unsigned_abs())
+    .min(max_base_asset_amount);
+    require_gt!(base_asset_amount_to_transfer, 0, PerpError::InvalidAmount);
+
+    // The liquidator buys a long below the oracle price or sells a short above it
+    let discount = oracle_price
+        .checked_mul(market.liquidation_transfer_discount as u128)
+        .and_then(|d| d.checked_div(RATIO_PRECISION as u128))
+        .ok_or(PerpError::MathOverflow)?;
+    let transfer_price = if was_long {
+        oracle_price.checked_sub(discount)
+    } else {
+        oracle_price.checked_add(discount)
+    }
+    .ok_or(PerpError::MathOverflow)?;
+    let quote_asset_amount = base_asset_amount_to_transfer
+        .checked_mul(transfer_price)
+        .and_then(|q| q.checked_div(PRECISION))
+        .ok_or(PerpError::MathOverflow)?;
+
+    let old_position = *position;
+    let mut pnl = position.reduce(base_asset_amount_to_transfer, quote_asset_amount)?;
+    market.update_open_interest(
+        old_position.base_asset_amount,
+        old_position.quote_asset_amount,
+        position.base_asset_amount,
+        position.quote_asset_amount,
+    )?;
+    position.clear_if_available();
+
+    // A loss the collateral cannot cover leaves the account bankrupt, so the rest of the
+    // position is closed against the vAMM, beyond the per-slot limit
+    let remaining_base_asset_amount = user
+        .find_position_mut(market_index)
+        .map(|p| p.base_asset_amount.unsigned_abs())
+        .unwrap_or(0);
+    if remaining_base_asset_amount > 0
+        && pnl < 0
+        && quote_to_collateral(pnl.unsigned_abs())? > user.collateral
+    {
+        market.record_liquidation(clock.slot, remaining_base_asset_amount)?;
+        let (_, remaining_pnl) =
+            reduce_position_against_amm(&mut user, &mut market, remaining_base_asset_amount)?;
+        pnl = pnl.checked_add(remaining_pnl).ok_or(PerpError::MathOverflow)?;
+    }
+    let deficit = user.settle_realized_pnl(pnl)?;
+
+    // The liquidator takes the position over at the transfer price, netting it against any
+    // opposite position of its own
+    let base_asset_amount_delta = if was_long {
+        base_asset_amount_to_transfer as i128
+    } else {
+        -(base_asset_amount_to_transfer as i128)
+    };
+    let liquidator_pnl = update_position_with_fill(
+        &mut liquidator_account,
+        &mut market,
+        base_asset_amount_delta,
+        quote_asset_amount,
+    )?;
+    let liquidator_shortfall = liquidator_account.settle_realized_pnl(liquidator_pnl)?;
+    require!(liquidator_shortfall == 0, PerpError::InsufficientCollateral);
+
+    require!(
+        liquidator_account.free_collateral(&market_oracle_map, MarginRequirementType::Initial)? >= 0,
+        PerpError::PositionCausesMarginCall
+    );
+
+    let insurance_draw = resolve_deficit(
+        &mut market,
+        deficit,
+        was_long,
+        ctx.accounts.insurance_vault.amount,
+    )?;
 
     user.operation_lock = false;
+    drop(user);
+    drop(liquidator_account);
+    drop(market);
+
+    transfer_between_vaults(
+        &ctx.accounts.token_program,
+        &ctx.accounts.program_state,
+        &ctx.accounts.insurance_vault,
+        &ctx.accounts.collateral_vault,
+        insurance_draw,
+    )?;
 
     Ok(())
 }
+
+/// Cancels the user's limit orders and its resting orders on the liquidated market's order book,
+/// which must be passed if the user has any left.
+fn cancel_open_orders(
+    user: &mut User,
+    user_key: Pubkey,
+    market_index: u16,
+    order_book: Option<&AccountLoader<OrderBook>>,
+) -> Result<()> {
+    cancel_all_limit_orders(user)?;
+    if let Some(order_book) = order_book {
+        cancel_user_orders(&mut *order_book.load_mut()?, user, user_key, market_index)?;
+    }
+
+    let has_book_orders = user
+        .positions
+        .iter()
+        .any(|p| p.market_index == market_index && (p.open_bids > 0 || p.open_asks > 0));
+    require!(!has_book_orders, PerpError::OrderBookMissing);
+
+    Ok(())
+}
+
+/// Covers a liquidation deficit from the insurance fund; whatever the fund cannot cover is
+/// borne by the side that profited from the loss, per the market's bankruptcy mode.
+/// Returns the amount to draw from the insurance vault.
+fn resolve_deficit(
+    market: &mut Market,
+    deficit: u64,
+    was_long: bool,
+    insurance_vault_balance: u64,
+) -> Result<u64> {
+    let insurance_draw = deficit.min(insurance_vault_balance);
+    market.absorb_deficit(deficit - insurance_draw, !was_long)?;
+
+    Ok(insurance_draw)
+}
+
+/// Transfers `amount` between the program's vaults, signed by the program state PDA.
+fn transfer_between_vaults<'info>(
+    token_program: &Program<'info, Token>,
+    program_state: &Account<'info, State>,
+    from: &Account<'info, TokenAccount>,
+    to: &Account<'info, TokenAccount>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let signer_seeds = &[&PROGRAM_SEED[..], &[program_state.bump]];
+    let signer = &[&signer_seeds[..]];
+
+    let cpi_accounts = Transfer {
+        from: from.to_account_info(),
+        to: to.to_account_info(),
+        authority: program_state.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer);
+    token::transfer(cpi_ctx, amount)
+}
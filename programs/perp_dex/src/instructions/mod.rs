@@ -1,15 +1,33 @@
 // Declare all module files
+pub mod admin;
+pub mod amm;
+pub mod auto_deleverage;
 pub mod create_market;
 pub mod funding;
 pub mod initialize;
+pub mod insurance_fund;
+pub mod limit_order;
 pub mod liquidation;
+pub mod order_book;
+pub mod taker_auction;
 pub mod trade;
+pub mod trigger_order;
+pub mod twap_order;
 pub mod user;
 
 // Re-export everything for easier access in other modules
+pub use admin::*;
+pub use amm::*;
+pub use auto_deleverage::*;
 pub use create_market::*;
 pub use funding::*;
 pub use initialize::*;
+pub use insurance_fund::*;
+pub use limit_order::*;
 pub use liquidation::*;
+pub use order_book::*;
+pub use taker_auction::*;
 pub use trade::*;
+pub use trigger_order::*;
+pub use twap_order::*;
 pub use user::*;
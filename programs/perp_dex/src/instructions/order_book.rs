@@ -0,0 +1,465 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{MARKET_SEED, ORDER_BOOK_SEED, PRECISION, USER_SEED};
+use crate::state::market::{Market, OrderBook};
+use crate::state::state::State;
+use crate::state::user::{LimitOrder, OrderType, User};
+use crate::error::PerpError;
+use crate::math::fees::calculate_trade_fee;
+use crate::math::margin::{
+    is_liquidatable, load_market_oracle_map, meets_initial_margin_requirement,
+    quote_to_collateral, MarketOracleMap,
+};
+use crate::instructions::trade::update_position_with_fill;
+use crate::validation::{
+    is_expired, validate_market_not_paused, validate_max_ts, validate_user_not_locked,
+};
+
+/// Creates the order book of an existing market. Only callable by the program admin.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct InitializeOrderBook<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    /// Program state (must match the admin).
+    #[account(has_one = admin)]
+    pub program_state: Account<'info, State>,
+
+    #[account(
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    /// The order book account (PDA) to initialize.
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + std::mem::size_of::<OrderBook>(),
+        seeds = [ORDER_BOOK_SEED, &market_index.to_le_bytes()],
+        bump
+    )]
+    pub order_book: AccountLoader<'info, OrderBook>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handle_initialize_order_book(
+    ctx: Context<InitializeOrderBook>,
+    market_index: u16,
+) -> Result<()> {
+    let mut order_book = ctx.accounts.order_book.load_init()?;
+    order_book.market_index = market_index;
+    order_book.bump = ctx.bumps.order_book;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct PlaceOrder<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [USER_SEED, authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [ORDER_BOOK_SEED, &market_index.to_le_bytes()],
+        bump = order_book.load()?.bump
+    )]
+    pub order_book: AccountLoader<'info, OrderBook>,
+}
+
+/// Places an order for `base_asset_amount` (positive bids, negative asks) at `price`.
+/// The order first takes every crossing resting order at the maker's price in price-time
+/// priority, paying the trade fee on the filled quote. What happens to the remainder depends
+/// on `order_type`: limit orders rest it, IOC cancels it and FOK fails; post-only orders
+/// fail instead of taking and rest in full. A resting order expires after `max_ts`.
+/// Expired orders and orders of makers that are below maintenance margin or cannot cover the
+/// loss a fill realizes are cancelled as they are reached instead of being filled, and a maker
+/// a fill leaves below maintenance margin has the rest of its order cancelled. Neither a maker's
+/// nor the taker's unfilled remainder rests below the market's minimum order size.
+/// The `User` accounts of crossed makers lead the remaining accounts, followed by the
+/// `[market, oracle]` pairs for every market the taker and those makers hold positions in;
+/// the taker's initial margin check covers resting orders.
+pub fn handle_place_order(
+    ctx: Context<PlaceOrder>,
+    _market_index: u16,
+    base_asset_amount: i128,
+    price: u128,
+    order_type: OrderType,
+    max_ts: Option<i64>,
+) -> Result<()> {
+    require!(base_asset_amount != 0, PerpError::InvalidAmount);
+    require_gt!(price, 0, PerpError::InvalidAmount);
+
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp;
+    let max_ts = validate_max_ts(max_ts, now)?;
+
+    let num_makers = ctx
+        .remaining_accounts
+        .iter()
+        .take_while(|info| AccountLoader::<User>::try_from(info).is_ok())
+        .count();
+    let (maker_accounts, market_oracle_accounts) = ctx.remaining_accounts.split_at(num_makers);
+    let market_oracle_map = load_market_oracle_map(market_oracle_accounts, &clock)?;
+
+    let user_key = ctx.accounts.user_account.key();
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let mut market = ctx.accounts.market.load_mut()?;
+    let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+    validate_user_not_locked(&user)?;
+    validate_market_not_paused(&market)?;
+    require_gte!(
+        base_asset_amount.unsigned_abs(),
+        market.min_order_size,
+        PerpError::OrderTooSmall
+    );
+
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
+
+    let is_bid = base_asset_amount > 0;
+    let mut base_asset_amount_remaining = base_asset_amount.unsigned_abs();
+    let mut quote_asset_amount_filled = 0u128;
+
+    while base_asset_amount_remaining > 0 {
+        let Some(maker_order) = order_book.best_order(!is_bid).copied() else {
+            break;
+        };
+        let crosses = if is_bid {
+            maker_order.price <= price
+        } else {
+            maker_order.price >= price
+        };
+        if !crosses {
+            break;
+        }
+        require_keys_neq!(maker_order.user, user_key, PerpError::CannotMatchOwnOrder);
+
+        let maker_info = maker_accounts
+            .iter()
+            .find(|info| info.key() == maker_order.user)
+            .ok_or(PerpError::MakerAccountMissing)?;
+        let maker_loader: AccountLoader<User> = AccountLoader::try_from(maker_info)?;
+        let mut maker = maker_loader.load_mut()?;
+        validate_user_not_locked(&maker)?;
+        maker.settle_funding_payment(&mut market)?;
+        maker.settle_socialized_loss(&mut market)?;
+
+        let fill_base = base_asset_amount_remaining.min(maker_order.base_asset_amount);
+        let fill_quote = fill_base
+            .checked_mul(maker_order.price)
+            .and_then(|q| q.checked_div(PRECISION))
+            .ok_or(PerpError::MathOverflow)?;
+        let taker_base_delta = if is_bid {
+            fill_base as i128
+        } else {
+            -(fill_base as i128)
+        };
+
+        // Orders that can no longer fill are pruned as they are reached
+        if is_expired(maker_order.max_ts, now)
+            || !can_maker_fill(
+                &maker,
+                market.market_index,
+                -taker_base_delta,
+                fill_quote,
+                &market_oracle_map,
+            )?
+        {
+            order_book.remove_order(!is_bid, 0);
+            release_open_orders(
+                &mut maker,
+                market.market_index,
+                !is_bid,
+                maker_order.base_asset_amount,
+            )?;
+            continue;
+        }
+
+        require!(
+            order_type != OrderType::PostOnly,
+            PerpError::PostOnlyWouldTake
+        );
+
+        // The maker's resting order converts into position, releasing its order exposure
+        let maker_position = maker.find_position_mut(market.market_index)?;
+        let open_orders = if is_bid {
+            &mut maker_position.open_asks
+        } else {
+            &mut maker_position.open_bids
+        };
+        *open_orders = open_orders
+            .checked_sub(fill_base)
+            .ok_or(PerpError::MathOverflow)?;
+        let maker_pnl =
+            update_position_with_fill(&mut maker, &mut market, -taker_base_delta, fill_quote)?;
+        maker.settle_realized_pnl(maker_pnl)?;
+
+        let taker_pnl =
+            update_position_with_fill(&mut user, &mut market, taker_base_delta, fill_quote)?;
+        let shortfall = user.settle_realized_pnl(taker_pnl)?;
+        require!(shortfall == 0, PerpError::InsufficientCollateral);
+
+        let maker_base_asset_amount_remaining = maker_order.base_asset_amount - fill_base;
+        if maker_base_asset_amount_remaining == 0 {
+            order_book.remove_order(!is_bid, 0);
+        } else if maker_base_asset_amount_remaining < market.min_order_size
+            || is_liquidatable(&maker, &market_oracle_map)?
+        {
+            // A remainder below the minimum order size, or a maker the fill left below
+            // maintenance margin, stops quoting
+            order_book.remove_order(!is_bid, 0);
+            release_open_orders(
+                &mut maker,
+                market.market_index,
+                !is_bid,
+                maker_base_asset_amount_remaining,
+            )?;
+        } else {
+            let resting_order = if is_bid {
+                &mut order_book.asks[0]
+            } else {
+                &mut order_book.bids[0]
+            };
+            resting_order.base_asset_amount -= fill_base;
+        }
+        drop(maker);
+
+        base_asset_amount_remaining -= fill_base;
+        quote_asset_amount_filled = quote_asset_amount_filled
+            .checked_add(fill_quote)
+            .ok_or(PerpError::MathOverflow)?;
+    }
+
+    // Only the taker side pays the trade fee
+    let fee = calculate_trade_fee(quote_asset_amount_filled, market.trade_fee_rate)?;
+    user.collateral = user
+        .collateral
+        .checked_sub(fee)
+        .ok_or(PerpError::InsufficientCollateral)?;
+    market.collect_fee(fee)?;
+
+    if order_type == OrderType::FillOrKill {
+        require!(base_asset_amount_remaining == 0, PerpError::FillOrKillNotFilled);
+    }
+
+    // A remainder below the minimum order size is cancelled rather than rested
+    if base_asset_amount_remaining >= market.min_order_size.max(1)
+        && order_type != OrderType::ImmediateOrCancel
+    {
+        order_book.insert_order(is_bid, user_key, price, base_asset_amount_remaining, max_ts)?;
+
+        let position = user.find_or_create_position_mut(market.market_index)?;
+        let open_orders = if is_bid {
+            &mut position.open_bids
+        } else {
+            &mut position.open_asks
+        };
+        *open_orders = open_orders
+            .checked_add(base_asset_amount_remaining)
+            .ok_or(PerpError::MathOverflow)?;
+    }
+
+    // Release the market borrow so the margin engine can load it from the remaining accounts.
+    drop(market);
+
+    require!(
+        meets_initial_margin_requirement(&user, market_oracle_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct CancelOrder<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [USER_SEED, authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        mut,
+        seeds = [ORDER_BOOK_SEED, &market_index.to_le_bytes()],
+        bump = order_book.load()?.bump
+    )]
+    pub order_book: AccountLoader<'info, OrderBook>,
+}
+
+/// Cancels one of the user's resting orders, releasing the margin it locked.
+pub fn handle_cancel_order(
+    ctx: Context<CancelOrder>,
+    market_index: u16,
+    order_id: u64,
+) -> Result<()> {
+    let user_key = ctx.accounts.user_account.key();
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let mut order_book = ctx.accounts.order_book.load_mut()?;
+    validate_user_not_locked(&user)?;
+
+    let (is_bid, index) = order_book
+        .find_order(order_id)
+        .ok_or(PerpError::OrderNotFound)?;
+    require_keys_eq!(
+        order_book.orders(is_bid)[index].user,
+        user_key,
+        PerpError::OrderNotFound
+    );
+
+    let order = order_book.remove_order(is_bid, index);
+    release_open_orders(&mut user, market_index, is_bid, order.base_asset_amount)
+}
+
+/// Cancels all of the user's resting orders in a market.
+pub fn handle_cancel_all_orders(ctx: Context<CancelOrder>, market_index: u16) -> Result<()> {
+    let user_key = ctx.accounts.user_account.key();
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let mut order_book = ctx.accounts.order_book.load_mut()?;
+    validate_user_not_locked(&user)?;
+
+    cancel_user_orders(&mut order_book, &mut user, user_key, market_index)
+}
+
+/// Context for anyone pruning expired orders in a market.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct PruneExpiredOrders<'info> {
+    #[account(
+        mut,
+        seeds = [ORDER_BOOK_SEED, &market_index.to_le_bytes()],
+        bump = order_book.load()?.bump
+    )]
+    pub order_book: AccountLoader<'info, OrderBook>,
+}
+
+/// Removes the expired resting orders and limit orders in `market_index` of the `User` accounts
+/// passed as remaining accounts, releasing the margin they locked.
+pub fn handle_prune_expired_orders(
+    ctx: Context<PruneExpiredOrders>,
+    market_index: u16,
+) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+    for user_info in ctx.remaining_accounts {
+        let user_loader: AccountLoader<User> = AccountLoader::try_from(user_info)?;
+        let mut user = user_loader.load_mut()?;
+        validate_user_not_locked(&user)?;
+
+        for is_bid in [true, false] {
+            while let Some(index) = order_book
+                .orders(is_bid)
+                .iter()
+                .position(|o| o.user == user_info.key() && is_expired(o.max_ts, now))
+            {
+                let order = order_book.remove_order(is_bid, index);
+                release_open_orders(&mut user, market_index, is_bid, order.base_asset_amount)?;
+            }
+        }
+
+        for order_index in 0..user.limit_orders.len() {
+            let order = user.limit_orders[order_index];
+            if !order.is_active
+                || order.market_index != market_index
+                || !is_expired(order.max_ts, now)
+            {
+                continue;
+            }
+            user.limit_orders[order_index] = LimitOrder::default();
+            release_open_orders(&mut user, market_index, order.is_long, order.base_asset_amount)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes every resting order of the user `user_key` from `order_book`, releasing the margin
+/// they locked.
+pub fn cancel_user_orders(
+    order_book: &mut OrderBook,
+    user: &mut User,
+    user_key: Pubkey,
+    market_index: u16,
+) -> Result<()> {
+    for is_bid in [true, false] {
+        while let Some(index) = order_book
+            .orders(is_bid)
+            .iter()
+            .position(|o| o.user == user_key)
+        {
+            let order = order_book.remove_order(is_bid, index);
+            release_open_orders(user, market_index, is_bid, order.base_asset_amount)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if `maker` is above maintenance margin and its collateral covers any loss the
+/// fill of `base_asset_amount` for `quote_asset_amount` would realize.
+fn can_maker_fill(
+    maker: &User,
+    market_index: u16,
+    base_asset_amount: i128,
+    quote_asset_amount: u128,
+    market_oracle_map: &MarketOracleMap,
+) -> Result<bool> {
+    if is_liquidatable(maker, market_oracle_map)? {
+        return Ok(false);
+    }
+
+    let mut position = *maker
+        .positions
+        .iter()
+        .find(|p| p.market_index == market_index && !p.is_available())
+        .ok_or(PerpError::PositionNotFound)?;
+    let pnl = position.apply_fill(base_asset_amount, quote_asset_amount)?;
+
+    Ok(pnl >= 0 || quote_to_collateral(pnl.unsigned_abs())? <= maker.collateral)
+}
+
+/// Releases a cancelled order's base from the position's open order totals, freeing the slot
+/// once nothing is left in it.
+pub fn release_open_orders(
+    user: &mut User,
+    market_index: u16,
+    is_bid: bool,
+    base_asset_amount: u128,
+) -> Result<()> {
+    let position = user.find_position_mut(market_index)?;
+    let open_orders = if is_bid {
+        &mut position.open_bids
+    } else {
+        &mut position.open_asks
+    };
+    *open_orders = open_orders
+        .checked_sub(base_asset_amount)
+        .ok_or(PerpError::MathOverflow)?;
+
+    position.clear_if_available();
+
+    Ok(())
+}
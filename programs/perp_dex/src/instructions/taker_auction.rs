@@ -0,0 +1,422 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{
+    JIT_AUCTION_DURATION_SLOTS, MARKET_SEED, PRECISION, TAKER_AUCTION_SEED, USER_SEED,
+};
+use crate::state::market::Market;
+use crate::state::user::{TakerAuction, User};
+use crate::error::PerpError;
+use crate::instructions::order_book::release_open_orders;
+use crate::instructions::trade::{
+    open_position_against_amm, read_oracle_price, update_position_with_fill,
+};
+use crate::math::amm;
+use crate::math::fees::calculate_trade_fee;
+use crate::math::margin::meets_initial_margin_requirement;
+use crate::validation::{
+    is_expired, validate_fill_price_within_oracle_band, validate_mark_oracle_divergence,
+    validate_market_not_paused, validate_max_ts, validate_order_not_expired,
+    validate_user_not_locked,
+};
+
+/// Context for a user opting in or out of filling taker auctions.
+#[derive(Accounts)]
+pub struct RegisterJitMaker<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [USER_SEED, authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, User>,
+}
+
+pub fn handle_register_jit_maker(
+    ctx: Context<RegisterJitMaker>,
+    is_jit_maker: bool,
+) -> Result<()> {
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    validate_user_not_locked(&user)?;
+    user.is_jit_maker = is_jit_maker;
+
+    Ok(())
+}
+
+/// Context for a taker recording a trade intent for JIT makers to fill.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct BeginTakerAuction<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        has_one = authority,
+        seeds = [USER_SEED, authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = TakerAuction::LEN,
+        seeds = [TAKER_AUCTION_SEED, user_account.key().as_ref()],
+        bump
+    )]
+    pub taker_auction: Account<'info, TakerAuction>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handle_begin_taker_auction(
+    ctx: Context<BeginTakerAuction>,
+    market_index: u16,
+    base_asset_amount: i128,
+    limit_price: u128,
+    max_ts: Option<i64>,
+) -> Result<()> {
+    require!(base_asset_amount != 0, PerpError::InvalidAmount);
+    validate_user_not_locked(&*ctx.accounts.user_account.load()?)?;
+    validate_market_not_paused(&*ctx.accounts.market.load()?)?;
+
+    let clock = Clock::get()?;
+    let max_ts = validate_max_ts(max_ts, clock.unix_timestamp)?;
+
+    let taker_auction = &mut ctx.accounts.taker_auction;
+    taker_auction.authority = ctx.accounts.authority.key();
+    taker_auction.user = ctx.accounts.user_account.key();
+    taker_auction.bump = ctx.bumps.taker_auction;
+    taker_auction.market_index = market_index;
+    taker_auction.base_asset_amount = base_asset_amount;
+    taker_auction.limit_price = limit_price;
+    taker_auction.start_slot = clock.slot;
+    taker_auction.max_ts = max_ts;
+
+    Ok(())
+}
+
+/// Context for a taker withdrawing its auction.
+#[derive(Accounts)]
+pub struct CancelTakerAuction<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        close = authority,
+        seeds = [TAKER_AUCTION_SEED, taker_auction.user.as_ref()],
+        bump = taker_auction.bump
+    )]
+    pub taker_auction: Account<'info, TakerAuction>,
+}
+
+/// Closes the auction without trading its unfilled remainder.
+pub fn handle_cancel_taker_auction(_ctx: Context<CancelTakerAuction>) -> Result<()> {
+    Ok(())
+}
+
+/// Context for a registered JIT maker filling part of a taker auction.
+#[derive(Accounts)]
+pub struct FillTakerAuction<'info> {
+    pub maker: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [USER_SEED, maker.key().as_ref()],
+        bump = maker_account.load()?.bump,
+        constraint = maker_account.key() != user_account.key() @ PerpError::CannotMatchOwnOrder
+    )]
+    pub maker_account: AccountLoader<'info, User>,
+
+    #[account(mut, address = taker_auction.user)]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        mut,
+        seeds = [TAKER_AUCTION_SEED, user_account.key().as_ref()],
+        bump = taker_auction.bump
+    )]
+    pub taker_auction: Account<'info, TakerAuction>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &taker_auction.market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
+}
+
+/// Fills up to `base_asset_amount` of the auction at `price`, which must be within the taker's
+/// limit, at least as good as the vAMM quote for the same size and within the market's oracle
+/// band. The taker pays the trade fee. Both users must meet the initial margin requirement
+/// afterwards.
+pub fn handle_fill_taker_auction(
+    ctx: Context<FillTakerAuction>,
+    base_asset_amount: u128,
+    price: u128,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let mut maker = ctx.accounts.maker_account.load_mut()?;
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let mut market = ctx.accounts.market.load_mut()?;
+    let taker_auction = &mut ctx.accounts.taker_auction;
+
+    validate_user_not_locked(&maker)?;
+    validate_user_not_locked(&user)?;
+    validate_market_not_paused(&market)?;
+    require!(maker.is_jit_maker, PerpError::MakerNotRegistered);
+
+    let end_slot = taker_auction
+        .start_slot
+        .checked_add(JIT_AUCTION_DURATION_SLOTS)
+        .ok_or(PerpError::MathOverflow)?;
+    require_gte!(end_slot, clock.slot, PerpError::AuctionEnded);
+    validate_order_not_expired(taker_auction.max_ts, clock.unix_timestamp)?;
+
+    let remaining = taker_auction.base_asset_amount.unsigned_abs();
+    require_gt!(base_asset_amount, 0, PerpError::InvalidAmount);
+    require_gte!(remaining, base_asset_amount, PerpError::InvalidAmount);
+
+    // Refresh the oracle and mark TWAPs the vAMM quote's spread is derived from
+    let oracle_price = read_oracle_price(&mut market, &ctx.accounts.oracle_price_feed)?;
+    market.update_mark_price_twap(clock.unix_timestamp)?;
+    validate_fill_price_within_oracle_band(&market, oracle_price, price)?;
+
+    // The maker must beat both the taker's limit and what the vAMM would give the fill
+    let is_long = taker_auction.base_asset_amount > 0;
+    let direction = if is_long {
+        amm::TradeDirection::Long
+    } else {
+        amm::TradeDirection::Short
+    };
+    let (new_quote_asset_reserve, _) = amm::calculate_swap_output(
+        base_asset_amount,
+        market.amm_base_asset_reserve,
+        market.amm_quote_asset_reserve,
+        direction,
+    )?;
+    let (amm_quote_asset_amount, _) = market.apply_spread(
+        amm::calculate_quote_asset_amount(
+            market.amm_quote_asset_reserve.abs_diff(new_quote_asset_reserve),
+            market.peg_multiplier,
+        )?,
+        direction,
+    )?;
+    let amm_price = amm_quote_asset_amount
+        .checked_mul(PRECISION)
+        .and_then(|n| n.checked_div(base_asset_amount))
+        .ok_or(PerpError::MathOverflow)?;
+    if is_long {
+        require_gte!(taker_auction.limit_price, price, PerpError::PriceSlippage);
+        require_gte!(amm_price, price, PerpError::PriceWorseThanAmm);
+    } else {
+        require_lte!(taker_auction.limit_price, price, PerpError::PriceSlippage);
+        require_lte!(amm_price, price, PerpError::PriceWorseThanAmm);
+    }
+
+    let quote_asset_amount = base_asset_amount
+        .checked_mul(price)
+        .and_then(|q| q.checked_div(PRECISION))
+        .ok_or(PerpError::MathOverflow)?;
+    let taker_base_delta = if is_long {
+        base_asset_amount as i128
+    } else {
+        -(base_asset_amount as i128)
+    };
+
+    for (account, base_delta) in [(&mut maker, -taker_base_delta), (&mut user, taker_base_delta)] {
+        account.settle_funding_payment(&mut market)?;
+        account.settle_socialized_loss(&mut market)?;
+        let pnl = update_position_with_fill(account, &mut market, base_delta, quote_asset_amount)?;
+        let shortfall = account.settle_realized_pnl(pnl)?;
+        require!(shortfall == 0, PerpError::InsufficientCollateral);
+    }
+
+    let fee = calculate_trade_fee(quote_asset_amount, market.trade_fee_rate)?;
+    user.collateral = user
+        .collateral
+        .checked_sub(fee)
+        .ok_or(PerpError::InsufficientCollateral)?;
+    market.collect_fee(fee)?;
+
+    taker_auction.base_asset_amount = taker_auction
+        .base_asset_amount
+        .checked_sub(taker_base_delta)
+        .ok_or(PerpError::MathOverflow)?;
+
+    // Release the market borrow so the margin engine can load it from the remaining accounts.
+    drop(market);
+
+    require!(
+        meets_initial_margin_requirement(&maker, ctx.remaining_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+    require!(
+        meets_initial_margin_requirement(&user, ctx.remaining_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+
+    Ok(())
+}
+
+/// Context for settling a taker auction once its maker window has closed.
+#[derive(Accounts)]
+pub struct SettleTakerAuction<'info> {
+    /// CHECK: The taker's authority, refunded the auction account's rent
+    #[account(mut, address = taker_auction.authority)]
+    pub authority: AccountInfo<'info>,
+
+    #[account(mut, address = taker_auction.user)]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [TAKER_AUCTION_SEED, user_account.key().as_ref()],
+        bump = taker_auction.bump
+    )]
+    pub taker_auction: Account<'info, TakerAuction>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &taker_auction.market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
+}
+
+/// Trades whatever makers left unfilled against the vAMM within the taker's limit price and
+/// closes the auction. Permissionless once the auction window has passed or the auction has
+/// expired. The auction is closed without trading if it has expired, the market is paused, the
+/// taker could not hold the remainder as a resting order within its initial margin, or the vAMM
+/// cannot fill the remainder within the limit price and the market's oracle band.
+pub fn handle_settle_taker_auction(ctx: Context<SettleTakerAuction>) -> Result<()> {
+    let taker_auction = &ctx.accounts.taker_auction;
+    let base_asset_amount = taker_auction.base_asset_amount;
+    if base_asset_amount == 0 {
+        return Ok(());
+    }
+
+    let clock = Clock::get()?;
+    let end_slot = taker_auction
+        .start_slot
+        .checked_add(JIT_AUCTION_DURATION_SLOTS)
+        .ok_or(PerpError::MathOverflow)?;
+    let expired = is_expired(taker_auction.max_ts, clock.unix_timestamp);
+    require!(clock.slot > end_slot || expired, PerpError::AuctionInProgress);
+
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    validate_user_not_locked(&user)?;
+    if expired || ctx.accounts.market.load()?.paused {
+        return Ok(());
+    }
+
+    // The remainder is checked against the taker's margin the way a resting order would be
+    let market_index = taker_auction.market_index;
+    let is_long = base_asset_amount > 0;
+    add_open_orders(&mut user, market_index, is_long, base_asset_amount.unsigned_abs())?;
+    let meets_margin = meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?;
+    release_open_orders(&mut user, market_index, is_long, base_asset_amount.unsigned_abs())?;
+    if !meets_margin {
+        return Ok(());
+    }
+
+    let mut market = ctx.accounts.market.load_mut()?;
+    let oracle_price = read_oracle_price(&mut market, &ctx.accounts.oracle_price_feed)?;
+    if !can_fill_against_amm(&market, base_asset_amount, taker_auction.limit_price, oracle_price)? {
+        return Ok(());
+    }
+    let mark_price_before = market.get_mark_price()?;
+
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
+
+    open_position_against_amm(
+        &mut user,
+        &mut market,
+        base_asset_amount,
+        taker_auction.limit_price,
+    )?;
+    validate_mark_oracle_divergence(&market, oracle_price, mark_price_before)?;
+
+    // Release the market borrow so the margin engine can load it from the remaining accounts.
+    drop(market);
+
+    require!(
+        meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+
+    Ok(())
+}
+
+/// Adds `base_asset_amount` to the user's open bids or asks in `market_index`.
+fn add_open_orders(
+    user: &mut User,
+    market_index: u16,
+    is_bid: bool,
+    base_asset_amount: u128,
+) -> Result<()> {
+    let position = user.find_or_create_position_mut(market_index)?;
+    let open_orders = if is_bid {
+        &mut position.open_bids
+    } else {
+        &mut position.open_asks
+    };
+    *open_orders = open_orders
+        .checked_add(base_asset_amount)
+        .ok_or(PerpError::MathOverflow)?;
+
+    Ok(())
+}
+
+/// Returns true if the vAMM can fill `base_asset_amount` (positive buys, negative sells) at an
+/// average price within `limit_price` and the fill keeps the mark price within the market's
+/// oracle band. The fill is simulated on a copy of the market.
+fn can_fill_against_amm(
+    market: &Market,
+    base_asset_amount: i128,
+    limit_price: u128,
+    oracle_price: u128,
+) -> Result<bool> {
+    let direction = if base_asset_amount > 0 {
+        amm::TradeDirection::Long
+    } else {
+        amm::TradeDirection::Short
+    };
+
+    let mut simulated_market = Box::new(*market);
+    let Ok(quote_asset_amount) =
+        simulated_market.swap_base_asset(base_asset_amount.unsigned_abs(), direction)
+    else {
+        return Ok(false);
+    };
+    let entry_price = quote_asset_amount
+        .checked_mul(PRECISION)
+        .and_then(|n| n.checked_div(base_asset_amount.unsigned_abs()))
+        .ok_or(PerpError::MathOverflow)?;
+    let within_limit = match direction {
+        amm::TradeDirection::Long => entry_price <= limit_price,
+        amm::TradeDirection::Short => entry_price >= limit_price,
+    };
+
+    let mark_price_before = market.get_mark_price()?;
+
+    Ok(within_limit
+        && validate_mark_oracle_divergence(&simulated_market, oracle_price, mark_price_before)
+            .is_ok())
+}
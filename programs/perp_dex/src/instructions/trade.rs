@@ -1,11 +1,15 @@
 use anchor_lang::prelude::*;
 use crate::state::market::Market;
-use crate::state::user::User;
+use crate::state::user::{OrderParams, OrderType, User};
 use crate::state::constants::{MARKET_SEED, USER_SEED, PRECISION};
 use crate::error::PerpError;
 use crate::math::amm;
+use crate::math::fees::calculate_trade_fee;
 use crate::math::margin::meets_initial_margin_requirement;
-use crate::validation::{validate_user_not_locked, validate_market_not_paused};
+use crate::validation::{
+    validate_mark_oracle_divergence, validate_market_not_paused, validate_max_ts,
+    validate_oracle_price_and_confidence, validate_user_not_locked,
+};
 
 #[derive(Accounts)]
 pub struct OpenPosition<'info> {
@@ -25,38 +29,118 @@ pub struct OpenPosition<'info> {
         bump = market.load()?.bump
     )]
     pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
 }
 
-pub fn handle_open_position(
-    ctx: Context<OpenPosition>,
-    base_asset_amount: i128,
-    limit_price: u128,
-) -> Result<()> {
+pub fn handle_open_position(ctx: Context<OpenPosition>, params: OrderParams) -> Result<()> {
+    require!(params.base_asset_amount != 0, PerpError::InvalidAmount);
+    validate_max_ts(params.max_ts, Clock::get()?.unix_timestamp)?;
+
     let mut user = ctx.accounts.user_account.load_mut()?;
     let mut market = ctx.accounts.market.load_mut()?;
 
     validate_user_not_locked(&user)?;
     validate_market_not_paused(&market)?;
 
+    // A vAMM trade always takes liquidity; post-only orders belong on the order book
+    require!(
+        params.order_type != OrderType::PostOnly,
+        PerpError::PostOnlyWouldTake
+    );
+
+    let oracle_price = read_oracle_price(&mut market, &ctx.accounts.oracle_price_feed)?;
+    // IOC sizing quotes the spreads, which depend on the mark TWAP, so bring it up to date first
+    market.update_mark_price_twap(Clock::get()?.unix_timestamp)?;
+    let mark_price_before = market.get_mark_price()?;
+
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
+
+    let is_long = params.base_asset_amount > 0;
+    let mut base_asset_amount = params.base_asset_amount.unsigned_abs();
+
+    // Only the part of the order opposing the position is traded, so it can never flip it
+    let position_base_asset_amount = user
+        .find_position_mut(market.market_index)
+        .map(|p| p.base_asset_amount)
+        .unwrap_or(0);
+    if params.reduce_only {
+        require!(
+            position_base_asset_amount != 0 && (position_base_asset_amount > 0) != is_long,
+            PerpError::ReduceOnlyIncreasesPosition
+        );
+        base_asset_amount = base_asset_amount.min(position_base_asset_amount.unsigned_abs());
+    }
+
+    // IOC trades the most the curve allows within the limit price and cancels the rest
+    if params.order_type == OrderType::ImmediateOrCancel {
+        let direction = if is_long {
+            amm::TradeDirection::Long
+        } else {
+            amm::TradeDirection::Short
+        };
+        base_asset_amount = base_asset_amount
+            .min(market.get_max_base_asset_amount_within_limit(params.limit_price, direction)?);
+        if base_asset_amount == 0 {
+            return Ok(());
+        }
+    }
+
+    if params.reduce_only {
+        close_position_against_amm(
+            &mut user,
+            &mut market,
+            base_asset_amount,
+            Some(params.limit_price),
+        )?;
+    } else {
+        let base_asset_amount = if is_long {
+            base_asset_amount as i128
+        } else {
+            -(base_asset_amount as i128)
+        };
+        open_position_against_amm(&mut user, &mut market, base_asset_amount, params.limit_price)?;
+    }
+
+    validate_mark_oracle_divergence(&market, oracle_price, mark_price_before)?;
+
+    // Release the market borrow so the margin engine can load it from the remaining accounts.
+    drop(market);
+
+    require!(
+        meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+
+    Ok(())
+}
+
+/// Trades `base_asset_amount` (positive buys, negative sells) against the vAMM within
+/// `limit_price`, charging the trade fee. Trades opposing the position reduce it first,
+/// and a trade that flips it opens the remainder at the trade's average price.
+/// Settling funding and socialized losses beforehand and the margin check afterwards
+/// are left to the caller.
+pub fn open_position_against_amm(
+    user: &mut User,
+    market: &mut Market,
+    base_asset_amount: i128,
+    limit_price: u128,
+) -> Result<()> {
     let direction = if base_asset_amount > 0 {
         amm::TradeDirection::Long
     } else {
         amm::TradeDirection::Short
     };
 
-    let (new_quote_asset_reserve, new_base_asset_reserve) = amm::calculate_swap_output(
-        base_asset_amount.abs(),
-        market.amm_base_asset_reserve,
-        market.amm_quote_asset_reserve,
-        direction,
-    )?;
-
+    market.update_mark_price_twap(Clock::get()?.unix_timestamp)?;
     let quote_asset_amount_acquired =
-        market.amm_quote_asset_reserve.abs_diff(new_quote_asset_reserve);
+        market.swap_base_asset(base_asset_amount.unsigned_abs(), direction)?;
 
     let entry_price = quote_asset_amount_acquired
         .checked_mul(PRECISION)
-        .and_then(|n| n.checked_div(base_asset_amount.abs() as u128))
+        .and_then(|n| n.checked_div(base_asset_amount.unsigned_abs()))
         .ok_or(PerpError::MathOverflow)?;
 
     match direction {
@@ -68,23 +152,18 @@ pub fn handle_open_position(
         }
     }
 
-    market.amm_base_asset_reserve = new_base_asset_reserve;
-    market.amm_quote_asset_reserve = new_quote_asset_reserve;
+    let fee = calculate_trade_fee(quote_asset_amount_acquired, market.trade_fee_rate)?;
+    user.collateral = user
+        .collateral
+        .checked_sub(fee)
+        .ok_or(PerpError::InsufficientCollateral)?;
+    market.collect_fee(fee)?;
 
-    let position = user.find_or_create_position_mut(market.market_index)?;
-    position.base_asset_amount = position
-        .base_asset_amount
-        .checked_add(base_asset_amount)
-        .ok_or(PerpError::MathOverflow)?;
-    position.quote_asset_amount = position
-        .quote_asset_amount
-        .checked_add(quote_asset_amount_acquired)
-        .ok_or(PerpError::MathOverflow)?;
-
-    require!(
-        meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?,
-        PerpError::PositionCausesMarginCall
-    );
+    // Reductions and flips realize PnL on the closed part into collateral
+    let pnl =
+        update_position_with_fill(user, market, base_asset_amount, quote_asset_amount_acquired)?;
+    let shortfall = user.settle_realized_pnl(pnl)?;
+    require!(shortfall == 0, PerpError::InsufficientCollateral);
 
     Ok(())
 }
@@ -108,64 +187,178 @@ pub struct ClosePosition<'info> {
         bump = market.load()?.bump
     )]
     pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
 }
 
 pub fn handle_close_position(
     ctx: Context<ClosePosition>,
     market_index: u16,
+    max_ts: Option<i64>,
 ) -> Result<()> {
+    close_position(ctx, market_index, None, max_ts)
+}
+
+/// Closes `base_asset_amount` of the position, realizing a proportional share of its PnL.
+pub fn handle_partial_close_position(
+    ctx: Context<ClosePosition>,
+    market_index: u16,
+    base_asset_amount: u128,
+    max_ts: Option<i64>,
+) -> Result<()> {
+    require_gt!(base_asset_amount, 0, PerpError::InvalidAmount);
+    close_position(ctx, market_index, Some(base_asset_amount), max_ts)
+}
+
+/// Closes `base_asset_amount` of the position in `market_index`, or all of it if `None`,
+/// unless the clock has passed `max_ts`.
+fn close_position(
+    ctx: Context<ClosePosition>,
+    market_index: u16,
+    base_asset_amount: Option<u128>,
+    max_ts: Option<i64>,
+) -> Result<()> {
+    validate_max_ts(max_ts, Clock::get()?.unix_timestamp)?;
+
     let mut user = ctx.accounts.user_account.load_mut()?;
     let mut market = ctx.accounts.market.load_mut()?;
 
     validate_user_not_locked(&user)?;
     validate_market_not_paused(&market)?;
 
-    let position_to_close = user.find_position_mut(market_index)?.clone();
+    let position_size = user
+        .find_position_mut(market_index)?
+        .base_asset_amount
+        .unsigned_abs();
 
-    if position_to_close.base_asset_amount == 0 {
+    if position_size == 0 {
         return err!(PerpError::NoPositionToClose);
     }
 
-    let base_asset_amount_to_close = -position_to_close.base_asset_amount;
-    let direction = if base_asset_amount_to_close > 0 {
-        amm::TradeDirection::Long
-    } else {
-        amm::TradeDirection::Short
-    };
+    let base_asset_amount_to_close = base_asset_amount.unwrap_or(position_size);
+    require_gte!(position_size, base_asset_amount_to_close, PerpError::InvalidAmount);
 
-    let (new_quote_asset_reserve, new_base_asset_reserve) = amm::calculate_swap_output(
-        base_asset_amount_to_close.abs(),
-        market.amm_base_asset_reserve,
-        market.amm_quote_asset_reserve,
-        direction,
-    )?;
+    let oracle_price = read_oracle_price(&mut market, &ctx.accounts.oracle_price_feed)?;
+    let mark_price_before = market.get_mark_price()?;
 
-    let quote_asset_returned = market.amm_quote_asset_reserve.abs_diff(new_quote_asset_reserve);
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
 
-    let pnl = if position_to_close.base_asset_amount > 0 {
-        quote_asset_returned as i128 - position_to_close.quote_asset_amount as i128
-    } else {
-        position_to_close.quote_asset_amount as i128 - quote_asset_returned as i128
-    };
+    close_position_against_amm(&mut user, &mut market, base_asset_amount_to_close, None)?;
 
-    if pnl > 0 {
-        user.collateral = user
-            .collateral
-            .checked_add((pnl / (PRECISION as i128)) as u64)
-            .ok_or(PerpError::MathOverflow)?;
-    } else {
-        user.collateral = user
-            .collateral
-            .checked_sub((pnl.abs() / (PRECISION as i128)) as u64)
+    validate_mark_oracle_divergence(&market, oracle_price, mark_price_before)
+}
+
+/// Closes `base_asset_amount` of the user's position in `market` against the vAMM, optionally
+/// requiring an average exit price no worse than `limit_price`, then settles the realized PnL
+/// and charges the trade fee. Settling funding and socialized losses beforehand is left to the caller.
+pub fn close_position_against_amm(
+    user: &mut User,
+    market: &mut Market,
+    base_asset_amount: u128,
+    limit_price: Option<u128>,
+) -> Result<()> {
+    let is_long = user.find_position_mut(market.market_index)?.base_asset_amount > 0;
+    let (quote_asset_amount, pnl) = reduce_position_against_amm(user, market, base_asset_amount)?;
+
+    if let Some(limit_price) = limit_price {
+        let exit_price = quote_asset_amount
+            .checked_mul(PRECISION)
+            .and_then(|n| n.checked_div(base_asset_amount))
             .ok_or(PerpError::MathOverflow)?;
+        // Closing a long sells, so the limit is a floor; closing a short buys, so it is a cap
+        if is_long {
+            require_gte!(exit_price, limit_price, PerpError::PriceSlippage);
+        } else {
+            require_gte!(limit_price, exit_price, PerpError::PriceSlippage);
+        }
     }
 
-    market.amm_base_asset_reserve = new_base_asset_reserve;
-    market.amm_quote_asset_reserve = new_quote_asset_reserve;
+    let shortfall = user.settle_realized_pnl(pnl)?;
+    require!(shortfall == 0, PerpError::InsufficientCollateral);
 
-    let position = user.find_position_mut(market_index)?;
-    *position = Default::default();
-    position.market_index = market_index;
+    let fee = calculate_trade_fee(quote_asset_amount, market.trade_fee_rate)?;
+    user.collateral = user
+        .collateral
+        .checked_sub(fee)
+        .ok_or(PerpError::InsufficientCollateral)?;
+    market.collect_fee(fee)?;
 
     Ok(())
 }
+
+/// Closes `base_asset_amount` of the user's position in `market` against the vAMM.
+/// Settling the realized PnL and charging fees are left to the caller.
+/// Returns the quote asset amount received or paid and the realized PnL (in price precision).
+pub fn reduce_position_against_amm(
+    user: &mut User,
+    market: &mut Market,
+    base_asset_amount: u128,
+) -> Result<(u128, i128)> {
+    let position = user.find_position_mut(market.market_index)?;
+    let old_position = *position;
+
+    // Reducing a long sells base into the vAMM, reducing a short buys it back
+    let direction = if old_position.base_asset_amount > 0 {
+        amm::TradeDirection::Short
+    } else {
+        amm::TradeDirection::Long
+    };
+
+    market.update_mark_price_twap(Clock::get()?.unix_timestamp)?;
+    let quote_asset_amount = market.swap_base_asset(base_asset_amount, direction)?;
+
+    let pnl = position.reduce(base_asset_amount, quote_asset_amount)?;
+    market.update_open_interest(
+        old_position.base_asset_amount,
+        old_position.quote_asset_amount,
+        position.base_asset_amount,
+        position.quote_asset_amount,
+    )?;
+
+    // A fully closed position frees its slot
+    position.clear_if_available();
+
+    Ok((quote_asset_amount, pnl))
+}
+
+/// Reads `market`'s oracle price from `oracle_info`, folds it into the oracle TWAP and records
+/// its confidence interval for the spread.
+pub fn read_oracle_price(market: &mut Market, oracle_info: &AccountInfo) -> Result<u128> {
+    require_keys_eq!(
+        market.oracle_price_feed,
+        oracle_info.key(),
+        PerpError::InvalidOraclePrice
+    );
+    let clock = Clock::get()?;
+    let (oracle_price, oracle_confidence) =
+        validate_oracle_price_and_confidence(oracle_info, &clock)?;
+    market.update_oracle_price_twap(oracle_price, clock.unix_timestamp)?;
+    market.last_oracle_confidence = oracle_confidence;
+
+    Ok(oracle_price)
+}
+
+/// Applies a fill to the user's position in `market` and keeps the market's open interest
+/// and the position's index snapshots in step. Returns the realized PnL (in price precision).
+pub fn update_position_with_fill(
+    user: &mut User,
+    market: &mut Market,
+    base_asset_amount: i128,
+    quote_asset_amount: u128,
+) -> Result<i128> {
+    let position = user.find_or_create_position_mut(market.market_index)?;
+    let old_position = *position;
+
+    let pnl = position.apply_fill(base_asset_amount, quote_asset_amount)?;
+    position.update_market_indices(market);
+    market.update_open_interest(
+        old_position.base_asset_amount,
+        old_position.quote_asset_amount,
+        position.base_asset_amount,
+        position.quote_asset_amount,
+    )?;
+
+    Ok(pnl)
+}
@@ -0,0 +1,166 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{MARKET_SEED, USER_SEED};
+use crate::state::market::Market;
+use crate::state::user::{TriggerOrder, TriggerOrderParams, TriggerSource, User};
+use crate::error::PerpError;
+use crate::instructions::trade::{close_position_against_amm, read_oracle_price};
+use crate::validation::{
+    is_expired, validate_mark_oracle_divergence, validate_market_not_paused, validate_max_ts,
+    validate_user_not_locked,
+};
+
+/// Context for a user managing their own trigger orders.
+#[derive(Accounts)]
+pub struct UpdateTriggerOrders<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [USER_SEED, authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, User>,
+}
+
+/// Stores a trigger order in the user's first free trigger order slot. The order only ever
+/// reduces the side of the position held in its market when it is placed.
+pub fn handle_place_trigger_order(
+    ctx: Context<UpdateTriggerOrders>,
+    params: TriggerOrderParams,
+) -> Result<()> {
+    require_gt!(params.trigger_price, 0, PerpError::InvalidAmount);
+    require_gt!(params.base_asset_amount, 0, PerpError::InvalidAmount);
+    let max_ts = validate_max_ts(params.max_ts, Clock::get()?.unix_timestamp)?;
+
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    validate_user_not_locked(&user)?;
+
+    let position_base_asset_amount = user.find_position_mut(params.market_index)?.base_asset_amount;
+    require!(position_base_asset_amount != 0, PerpError::PositionNotFound);
+
+    let trigger_order = user
+        .trigger_orders
+        .iter_mut()
+        .find(|o| !o.is_active)
+        .ok_or(PerpError::TriggerOrdersFull)?;
+    *trigger_order = TriggerOrder {
+        trigger_price: params.trigger_price,
+        limit_price: params.limit_price,
+        base_asset_amount: params.base_asset_amount,
+        market_index: params.market_index,
+        condition: params.condition as u8,
+        source: params.source as u8,
+        is_active: true,
+        reduces_long: position_base_asset_amount > 0,
+        _padding: [0; 2],
+        max_ts,
+    };
+
+    Ok(())
+}
+
+/// Removes the trigger order in slot `order_index`.
+pub fn handle_cancel_trigger_order(
+    ctx: Context<UpdateTriggerOrders>,
+    order_index: u8,
+) -> Result<()> {
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    validate_user_not_locked(&user)?;
+
+    let trigger_order = user
+        .trigger_orders
+        .get_mut(order_index as usize)
+        .filter(|o| o.is_active)
+        .ok_or(PerpError::OrderNotFound)?;
+    *trigger_order = TriggerOrder::default();
+
+    Ok(())
+}
+
+/// Context for a keeper executing a user's trigger order.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct ExecuteTriggerOrder<'info> {
+    pub keeper: Signer<'info>,
+
+    #[account(mut)]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
+}
+
+/// Executes the trigger order in slot `order_index` once its condition holds, closing up to its
+/// size of the position against the vAMM within its limit price. The order is consumed; if the
+/// position is gone or has flipped to the other side, or the order is past its expiry, it is
+/// simply cleared.
+pub fn handle_trigger_order(
+    ctx: Context<ExecuteTriggerOrder>,
+    market_index: u16,
+    order_index: u8,
+) -> Result<()> {
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let mut market = ctx.accounts.market.load_mut()?;
+
+    validate_user_not_locked(&user)?;
+    validate_market_not_paused(&market)?;
+
+    let trigger_order = *user
+        .trigger_orders
+        .get(order_index as usize)
+        .filter(|o| o.is_active)
+        .ok_or(PerpError::OrderNotFound)?;
+    require_eq!(trigger_order.market_index, market_index, PerpError::InvalidMarketIndex);
+
+    if is_expired(trigger_order.max_ts, Clock::get()?.unix_timestamp) {
+        user.trigger_orders[order_index as usize] = TriggerOrder::default();
+        return Ok(());
+    }
+
+    let oracle_price = read_oracle_price(&mut market, &ctx.accounts.oracle_price_feed)?;
+    let mark_price_before = market.get_mark_price()?;
+    let price = match trigger_order.get_source()? {
+        TriggerSource::Oracle => oracle_price,
+        TriggerSource::Mark => mark_price_before,
+    };
+    require!(
+        trigger_order.is_triggered(price)?,
+        PerpError::TriggerConditionNotMet
+    );
+
+    user.trigger_orders[order_index as usize] = TriggerOrder::default();
+
+    let position_base_asset_amount = user
+        .find_position_mut(market_index)
+        .map(|p| p.base_asset_amount)
+        .unwrap_or(0);
+    if position_base_asset_amount == 0
+        || (position_base_asset_amount > 0) != trigger_order.reduces_long
+    {
+        return Ok(());
+    }
+
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
+
+    // Reduce-only: never more than the position, so the order cannot flip it
+    let base_asset_amount = trigger_order
+        .base_asset_amount
+        .min(position_base_asset_amount.unsigned_abs());
+    close_position_against_amm(
+        &mut user,
+        &mut market,
+        base_asset_amount,
+        Some(trigger_order.limit_price),
+    )?;
+
+    validate_mark_oracle_divergence(&market, oracle_price, mark_price_before)
+}
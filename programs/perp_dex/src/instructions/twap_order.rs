@@ -0,0 +1,162 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{MARKET_SEED, USER_SEED};
+use crate::state::market::Market;
+use crate::state::user::{TwapOrder, TwapOrderParams, User};
+use crate::error::PerpError;
+use crate::instructions::trade::{open_position_against_amm, read_oracle_price};
+use crate::math::margin::meets_initial_margin_requirement;
+use crate::validation::{
+    is_expired, validate_mark_oracle_divergence, validate_market_not_paused, validate_max_ts,
+    validate_user_not_locked,
+};
+
+/// Context for a user managing their own TWAP orders.
+#[derive(Accounts)]
+pub struct UpdateTwapOrders<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [USER_SEED, authority.key().as_ref()],
+        bump = user_account.load()?.bump
+    )]
+    pub user_account: AccountLoader<'info, User>,
+}
+
+/// Stores a TWAP order in the user's first free TWAP order slot. The first slice can execute
+/// straight away.
+pub fn handle_place_twap_order(
+    ctx: Context<UpdateTwapOrders>,
+    params: TwapOrderParams,
+) -> Result<()> {
+    require!(params.base_asset_amount != 0, PerpError::InvalidAmount);
+    require_gt!(params.slice_base_asset_amount, 0, PerpError::InvalidAmount);
+    require_gte!(
+        params.base_asset_amount.unsigned_abs(),
+        params.slice_base_asset_amount,
+        PerpError::InvalidAmount
+    );
+    require_gt!(params.interval, 0, PerpError::InvalidAmount);
+
+    let now = Clock::get()?.unix_timestamp;
+    let max_ts = validate_max_ts(params.max_ts, now)?;
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    validate_user_not_locked(&user)?;
+
+    let twap_order = user
+        .twap_orders
+        .iter_mut()
+        .find(|o| !o.is_active)
+        .ok_or(PerpError::TwapOrdersFull)?;
+    *twap_order = TwapOrder {
+        base_asset_amount_remaining: params.base_asset_amount.unsigned_abs(),
+        slice_base_asset_amount: params.slice_base_asset_amount,
+        limit_price: params.limit_price,
+        interval: params.interval,
+        next_slice_ts: now,
+        max_ts,
+        market_index: params.market_index,
+        is_long: params.base_asset_amount > 0,
+        is_active: true,
+        _padding: [0; 4],
+    };
+
+    Ok(())
+}
+
+/// Removes the TWAP order in slot `order_index`, abandoning its unexecuted slices.
+pub fn handle_cancel_twap_order(ctx: Context<UpdateTwapOrders>, order_index: u8) -> Result<()> {
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    validate_user_not_locked(&user)?;
+
+    let twap_order = user
+        .twap_orders
+        .get_mut(order_index as usize)
+        .filter(|o| o.is_active)
+        .ok_or(PerpError::OrderNotFound)?;
+    *twap_order = TwapOrder::default();
+
+    Ok(())
+}
+
+/// Context for a keeper executing the next slice of a user's TWAP order.
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct ExecuteTwapSlice<'info> {
+    pub keeper: Signer<'info>,
+
+    #[account(mut)]
+    pub user_account: AccountLoader<'info, User>,
+
+    #[account(
+        mut,
+        seeds = [MARKET_SEED, &market_index.to_le_bytes()],
+        bump = market.load()?.bump
+    )]
+    pub market: AccountLoader<'info, Market>,
+
+    /// CHECK: Oracle account, validated in handler
+    pub oracle_price_feed: AccountInfo<'info>,
+}
+
+/// Trades the next slice of the TWAP order in slot `order_index` against the vAMM within its
+/// limit price, once its interval has elapsed. The user must meet the initial margin requirement
+/// afterwards. The order is cleared once fully executed, or without trading once past its expiry.
+pub fn handle_execute_twap_slice(
+    ctx: Context<ExecuteTwapSlice>,
+    market_index: u16,
+    order_index: u8,
+) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    let mut user = ctx.accounts.user_account.load_mut()?;
+    let mut market = ctx.accounts.market.load_mut()?;
+
+    validate_user_not_locked(&user)?;
+    validate_market_not_paused(&market)?;
+
+    let twap_order = *user
+        .twap_orders
+        .get(order_index as usize)
+        .filter(|o| o.is_active)
+        .ok_or(PerpError::OrderNotFound)?;
+    require_eq!(twap_order.market_index, market_index, PerpError::InvalidMarketIndex);
+
+    if is_expired(twap_order.max_ts, now) {
+        user.twap_orders[order_index as usize] = TwapOrder::default();
+        return Ok(());
+    }
+    require_gte!(now, twap_order.next_slice_ts, PerpError::TwapSliceNotReady);
+
+    let oracle_price = read_oracle_price(&mut market, &ctx.accounts.oracle_price_feed)?;
+    let mark_price_before = market.get_mark_price()?;
+
+    user.settle_funding_payment(&mut market)?;
+    user.settle_socialized_loss(&mut market)?;
+
+    let slice = twap_order.get_next_slice();
+    open_position_against_amm(&mut user, &mut market, slice, twap_order.limit_price)?;
+    validate_mark_oracle_divergence(&market, oracle_price, mark_price_before)?;
+
+    let twap_order = &mut user.twap_orders[order_index as usize];
+    twap_order.base_asset_amount_remaining = twap_order
+        .base_asset_amount_remaining
+        .checked_sub(slice.unsigned_abs())
+        .ok_or(PerpError::MathOverflow)?;
+    twap_order.next_slice_ts = now
+        .checked_add(twap_order.interval)
+        .ok_or(PerpError::MathOverflow)?;
+    if twap_order.base_asset_amount_remaining == 0 {
+        *twap_order = TwapOrder::default();
+    }
+
+    // Release the market borrow so the margin engine can load it from the remaining accounts.
+    drop(market);
+
+    require!(
+        meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?,
+        PerpError::PositionCausesMarginCall
+    );
+
+    Ok(())
+}
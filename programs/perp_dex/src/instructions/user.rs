@@ -1,11 +1,13 @@
 use anchor_lang::prelude::*;
+use anchor_lang::system_program;
 use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
 
-use crate::state::constants::{USER_SEED, VAULT_SEED, COLLATERAL_PRECISION};
+use crate::state::constants::{MARKET_SEED, USER_SEED, VAULT_SEED, COLLATERAL_PRECISION};
+use crate::state::market::Market;
 use crate::state::state::State;
-use crate::state::user::User;
+use crate::state::user::{LegacyUser, User};
 use crate::error::PerpError;
-use crate::math::margin::meets_initial_margin_requirement;
+use crate::math::margin::{collateral_to_quote, load_market_oracle_map, MarginRequirementType};
 use crate::validation::validate_user_not_locked;
 
 /// Initializes a new user account.
@@ -113,14 +115,22 @@ pub fn handle_withdraw_collateral(ctx: Context<WithdrawCollateral>, amount: u64)
     let mut user = ctx.accounts.user_account.load_mut()?;
     validate_user_not_locked(&user)?;
 
-    // Subtract collateral and check margin
-    let free_collateral = user.collateral.checked_sub(amount).ok_or(PerpError::InsufficientCollateral)?;
-
+    // Withdrawals are capped by free collateral, so unrealized losses and unsettled funding count
+    let market_oracle_map = load_market_oracle_map(&ctx.remaining_accounts, &Clock::get()?)?;
+    let free_collateral =
+        user.free_collateral(&market_oracle_map, MarginRequirementType::Initial)?;
     require!(
-        meets_initial_margin_requirement(&user, &ctx.remaining_accounts)?,
+        free_collateral >= collateral_to_quote(amount)? as i128,
         PerpError::WithdrawalCausesMarginCall
     );
 
+    // Save reduced balance
+    user.collateral = user
+        .collateral
+        .checked_sub(amount)
+        .ok_or(PerpError::InsufficientCollateral)?;
+    drop(user);
+
     // Transfer funds using signer seeds
     let state_bump = ctx.accounts.program_state.bump;
     let signer_seeds = &[&crate::state::constants::PROGRAM_SEED[..], &[state_bump]];
@@ -134,9 +144,87 @@ pub fn handle_withdraw_collateral(ctx: Context<WithdrawCollateral>, amount: u64)
     let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer);
     token::transfer(cpi_ctx, amount)?;
 
-    // Save reduced balance
-    let mut user = ctx.accounts.user_account.load_mut()?;
-    user.collateral = free_collateral;
+    Ok(())
+}
+
+/// Grows a user account created with the original user layout to the current one.
+#[derive(Accounts)]
+#[instruction(authority: Pubkey)]
+pub struct MigrateUser<'info> {
+    /// Pays the extra rent.
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: User account in the original layout, which `AccountLoader` cannot load until it has
+    /// been rewritten. Its address and owner are checked here, its discriminator in the handler.
+    #[account(
+        mut,
+        seeds = [USER_SEED, authority.as_ref()],
+        bump,
+        owner = crate::ID
+    )]
+    pub user_account: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Rewrites a user account from the original layout into the current one, growing it and
+/// charging the extra rent to the payer. Each open position joins its market's open interest
+/// and snapshots the market's current indexes.
+pub fn handle_migrate_user(ctx: Context<MigrateUser>, _authority: Pubkey) -> Result<()> {
+    let user_info = ctx.accounts.user_account.to_account_info();
+    let legacy_len = 8 + std::mem::size_of::<LegacyUser>();
+    let new_len = 8 + std::mem::size_of::<User>();
+    require_eq!(user_info.data_len(), legacy_len, PerpError::AccountAlreadyMigrated);
+    let legacy_user: LegacyUser = {
+        let data = user_info.try_borrow_data()?;
+        require!(
+            data.starts_with(&User::DISCRIMINATOR),
+            ErrorCode::AccountDiscriminatorMismatch
+        );
+        *bytemuck::from_bytes(&data[8..legacy_len])
+    };
+
+    let rent_shortfall = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(user_info.lamports());
+    if rent_shortfall > 0 {
+        let cpi_accounts = system_program::Transfer {
+            from: ctx.accounts.payer.to_account_info(),
+            to: user_info.clone(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.system_program.to_account_info(), cpi_accounts);
+        system_program::transfer(cpi_ctx, rent_shortfall)?;
+    }
+    user_info.realloc(new_len, true)?;
+
+    let mut data = user_info.try_borrow_mut_data()?;
+    data[8..].fill(0);
+    let user: &mut User = bytemuck::from_bytes_mut(&mut data[8..new_len]);
+    user.migrate_from(&legacy_user)?;
+
+    // Migrated markets start with no open interest, so each position adds its own and
+    // starts from the market's current funding and socialized loss indexes
+    for position in user.positions.iter_mut().filter(|p| p.base_asset_amount != 0) {
+        let (market_key, _) = Pubkey::find_program_address(
+            &[MARKET_SEED, &position.market_index.to_le_bytes()],
+            &crate::ID,
+        );
+        let market_info = ctx
+            .remaining_accounts
+            .iter()
+            .find(|info| info.key() == market_key)
+            .ok_or(PerpError::MarketAccountMissing)?;
+        require_eq!(
+            market_info.data_len(),
+            8 + std::mem::size_of::<Market>(),
+            PerpError::MarketNotMigrated
+        );
+        let market_loader: AccountLoader<Market> = AccountLoader::try_from(market_info)?;
+        let mut market = market_loader.load_mut()?;
+        market.update_open_interest(0, 0, position.base_asset_amount, position.quote_asset_amount)?;
+        position.update_market_indices(&market);
+    }
 
     Ok(())
 }
@@ -11,6 +11,8 @@ pub mod validation;
 
 // Make modules public for use in the program
 use instructions::*;
+use state::market::BankruptcyMode;
+use state::user::{OrderParams, OrderType, TriggerOrderParams, TwapOrderParams};
 use state::constants::PROGRAM_SEED;
 
 declare_id!("perpFC8a13h45b2n3sUKG5aD5EwB2gXcnm5FL12h4m");
@@ -63,6 +65,224 @@ pub mod perp_dex {
         )
     }
 
+    /// Sets the share of positive unrealized PnL excluded from account equity.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `unrealized_pnl_haircut` - Haircut on positive PnL (scaled by 1_000_000).
+    pub fn update_market_unrealized_pnl_haircut(
+        ctx: Context<AdminUpdateMarket>,
+        unrealized_pnl_haircut: u64,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_unrealized_pnl_haircut(ctx, unrealized_pnl_haircut)
+    }
+
+    /// Sets the share of trade fees allotted to the insurance fund.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `insurance_fee_share` - Insurance share of each fee (scaled by 1_000_000).
+    pub fn update_market_insurance_fee_share(
+        ctx: Context<AdminUpdateMarket>,
+        insurance_fee_share: u64,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_insurance_fee_share(ctx, insurance_fee_share)
+    }
+
+    /// Sets how the market absorbs deficits the insurance fund cannot cover.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `bankruptcy_mode` - Socialize the loss or auto-deleverage counterparties.
+    pub fn update_market_bankruptcy_mode(
+        ctx: Context<AdminUpdateMarket>,
+        bankruptcy_mode: BankruptcyMode,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_bankruptcy_mode(ctx, bankruptcy_mode)
+    }
+
+    /// Sets the partial liquidation target buffer and the per-slot liquidation limit.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `liquidation_margin_buffer_ratio` - Buffer above maintenance restored by liquidations (scaled by 1_000_000).
+    /// * `max_liquidation_base_per_slot` - Maximum base liquidated per slot, zero for no limit.
+    pub fn update_market_liquidation_params(
+        ctx: Context<AdminUpdateMarket>,
+        liquidation_margin_buffer_ratio: u64,
+        max_liquidation_base_per_slot: u128,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_liquidation_params(
+            ctx,
+            liquidation_margin_buffer_ratio,
+            max_liquidation_base_per_slot,
+        )
+    }
+
+    /// Sets the share of the liquidation fee paid to the liquidator.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `liquidator_fee_share` - Liquidator share of each liquidation fee (scaled by 1_000_000).
+    pub fn update_market_liquidator_fee_share(
+        ctx: Context<AdminUpdateMarket>,
+        liquidator_fee_share: u64,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_liquidator_fee_share(ctx, liquidator_fee_share)
+    }
+
+    /// Sets the maximum funding rate per period as a share of the oracle TWAP.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `max_funding_rate` - Maximum funding rate per period (scaled by 1_000_000).
+    pub fn update_market_max_funding_rate(
+        ctx: Context<AdminUpdateMarket>,
+        max_funding_rate: u64,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_max_funding_rate(ctx, max_funding_rate)
+    }
+
+    /// Moves a market's vAMM peg toward the oracle price, charging the cost of the repeg
+    /// to the market's fee pool. Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `new_peg_multiplier` - The new peg multiplier (scaled by 1_000_000). The resulting mark
+    ///   price must lie between the current mark price and the oracle price.
+    pub fn repeg_amm(ctx: Context<AdminUpdateAmm>, new_peg_multiplier: u128) -> Result<()> {
+        instructions::amm::handle_repeg_amm(ctx, new_peg_multiplier)
+    }
+
+    /// Scales a market's vAMM reserves, changing its liquidity depth while keeping the mark
+    /// price. The cost to the vAMM is charged to the market's fee pool, which must cover it.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `scale_factor` - The factor applied to both reserves (scaled by 1_000_000).
+    pub fn update_k(ctx: Context<AdminUpdateMarket>, scale_factor: u64) -> Result<()> {
+        instructions::amm::handle_update_k(ctx, scale_factor)
+    }
+
+    /// Configures the vAMM's self-tuning on funding updates: above the fee pool target the peg
+    /// is nudged toward the oracle TWAP and k grows, below it k shrinks.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `amm_fee_pool_target` - Fee pool level separating surplus from deficit (in collateral precision).
+    /// * `max_amm_adjustment_ratio` - Most the reserves and peg move per funding update
+    ///   (scaled by 1_000_000), zero to disable the adjustment.
+    /// * `min_k_constant` - Lowest k the vAMM may shrink to.
+    /// * `max_k_constant` - Highest k the vAMM may grow to.
+    pub fn update_market_amm_adjustment_params(
+        ctx: Context<AdminUpdateMarket>,
+        amm_fee_pool_target: u64,
+        max_amm_adjustment_ratio: u64,
+        min_k_constant: u128,
+        max_k_constant: u128,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_amm_adjustment_params(
+            ctx,
+            amm_fee_pool_target,
+            max_amm_adjustment_ratio,
+            min_k_constant,
+            max_k_constant,
+        )
+    }
+
+    /// Sets the vAMM's bid/ask spread parameters. Each side's spread starts at the base spread
+    /// and widens with inventory skew, oracle confidence and mark-oracle divergence, up to the cap.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `base_spread` - Spread charged on each side (scaled by 1_000_000).
+    /// * `max_spread` - Cap on each side's spread (scaled by 1_000_000).
+    pub fn update_market_spread_params(
+        ctx: Context<AdminUpdateMarket>,
+        base_spread: u64,
+        max_spread: u64,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_spread_params(ctx, base_spread, max_spread)
+    }
+
+    /// Sets the maximum divergence of the mark price from the oracle price that a vAMM fill may
+    /// leave. Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `max_mark_oracle_divergence_bps` - Maximum divergence in basis points, zero for no limit.
+    pub fn update_market_max_mark_oracle_divergence(
+        ctx: Context<AdminUpdateMarket>,
+        max_mark_oracle_divergence_bps: u64,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_max_mark_oracle_divergence(
+            ctx,
+            max_mark_oracle_divergence_bps,
+        )
+    }
+
+    /// Sets the discount to oracle at which liquidators take over positions.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `liquidation_transfer_discount` - Discount to oracle (scaled by 1_000_000).
+    pub fn update_market_liquidation_transfer_discount(
+        ctx: Context<AdminUpdateMarket>,
+        liquidation_transfer_discount: u64,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_liquidation_transfer_discount(
+            ctx,
+            liquidation_transfer_discount,
+        )
+    }
+
+    /// Sets the smallest order size accepted on the market's order book.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `min_order_size` - Minimum order size (in base precision).
+    pub fn update_market_min_order_size(
+        ctx: Context<AdminUpdateMarket>,
+        min_order_size: u128,
+    ) -> Result<()> {
+        instructions::admin::handle_update_market_min_order_size(ctx, min_order_size)
+    }
+
+    /// Withdraws protocol fees from a market's fee pool to `recipient`. The fee pool must keep
+    /// enough to cover the cost to the vAMM of moving its mark price to the oracle TWAP.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `amount` - The amount to withdraw (in collateral precision).
+    pub fn withdraw_fee_pool(ctx: Context<WithdrawFeePool>, amount: u64) -> Result<()> {
+        instructions::admin::handle_withdraw_fee_pool(ctx, amount)
+    }
+
+    /// Grows a market account created with the original market layout to the current one and
+    /// sets the appended fields to their defaults, starting the price TWAPs at the current mark
+    /// and oracle prices. Open interest starts at zero and is rebuilt by `migrate_user`.
+    /// The admin pays the extra rent.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to migrate.
+    pub fn migrate_market(ctx: Context<MigrateMarket>, market_index: u16) -> Result<()> {
+        instructions::admin::handle_migrate_market(ctx, market_index)
+    }
+
     /// Creates a user account PDA to store their positions and collateral.
     ///
     /// # Arguments
@@ -71,6 +291,19 @@ pub mod perp_dex {
         instructions::user::handle_create_user(ctx)
     }
 
+    /// Rewrites a user account created with the original user layout into the current one,
+    /// growing it. Positions start from their market's current funding and socialized loss
+    /// indexes and are added to its open interest, so the already migrated market of every open
+    /// position must be passed in `remaining_accounts`.
+    /// Permissionless; the payer covers the extra rent.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `authority` - The authority of the user account to migrate.
+    pub fn migrate_user(ctx: Context<MigrateUser>, authority: Pubkey) -> Result<()> {
+        instructions::user::handle_migrate_user(ctx, authority)
+    }
+
     /// Deposits collateral into the user's account.
     ///
     /// # Arguments
@@ -82,6 +315,7 @@ pub mod perp_dex {
 
     /// Withdraws collateral from the user's account.
     /// Fails if the withdrawal would push the user below the initial margin requirement.
+    /// Remaining accounts must hold a `[market, oracle]` pair for every market the user has a position in.
     ///
     /// # Arguments
     /// * `ctx` - The context for the instruction.
@@ -91,17 +325,17 @@ pub mod perp_dex {
     }
 
     /// Opens a new long or short position or modifies an existing one.
+    /// Remaining accounts must hold a `[market, oracle]` pair for every market the user has a position in.
     ///
     /// # Arguments
     /// * `ctx` - The context for the instruction.
-    /// * `base_asset_amount` - The amount of the base asset to trade. Positive for long, negative for short.
-    /// * `limit_price` - The price limit for the trade. The trade will only execute if the resulting price is better.
-    pub fn open_position(
-        ctx: Context<OpenPosition>,
-        base_asset_amount: i128,
-        limit_price: u128,
-    ) -> Result<()> {
-        instructions::trade::handle_open_position(ctx, base_asset_amount, limit_price)
+    /// * `params` - The signed base asset amount to trade, the limit price the average price must be
+    ///   better than, whether the order may only reduce the existing position, and the order type.
+    ///   IOC orders are sized down to what fills within the limit; post-only orders are rejected.
+    ///   The trade fails once the clock passes `max_ts`, if set, or if it pushes the mark price
+    ///   beyond the market's maximum divergence from the oracle price.
+    pub fn open_position(ctx: Context<OpenPosition>, params: OrderParams) -> Result<()> {
+        instructions::trade::handle_open_position(ctx, params)
     }
 
     /// Closes an existing position.
@@ -109,11 +343,45 @@ pub mod perp_dex {
     /// # Arguments
     /// * `ctx` - The context for the instruction.
     /// * `market_index` - The index of the market to close the position in.
-    pub fn close_position(ctx: Context<ClosePosition>, market_index: u16) -> Result<()> {
-        instructions::trade::handle_close_position(ctx, market_index)
+    /// * `max_ts` - Optional positive timestamp after which the close is rejected.
+    pub fn close_position(
+        ctx: Context<ClosePosition>,
+        market_index: u16,
+        max_ts: Option<i64>,
+    ) -> Result<()> {
+        instructions::trade::handle_close_position(ctx, market_index, max_ts)
+    }
+
+    /// Closes part of an existing position, realizing a proportional share of its PnL.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to reduce the position in.
+    /// * `base_asset_amount` - The amount of base asset to close.
+    /// * `max_ts` - Optional positive timestamp after which the close is rejected.
+    pub fn partial_close_position(
+        ctx: Context<ClosePosition>,
+        market_index: u16,
+        base_asset_amount: u128,
+        max_ts: Option<i64>,
+    ) -> Result<()> {
+        instructions::trade::handle_partial_close_position(
+            ctx,
+            market_index,
+            base_asset_amount,
+            max_ts,
+        )
     }
 
     /// Liquidates a user's position if their margin ratio is below the maintenance requirement.
+    /// Only enough base is closed against the vAMM to restore the market's target margin buffer,
+    /// subject to the market's per-slot liquidation limit. A user the loss leaves bankrupt is
+    /// closed out in full. The user's limit orders and its resting orders on the market's order
+    /// book, which must be passed if it holds any, are cancelled first.
+    /// The realized PnL is settled into collateral and the liquidation fee is split between the
+    /// liquidator's user account and the insurance fund, which also covers any resulting deficit.
+    /// Deficits beyond the insurance fund are socialized or queued for ADL per the market's bankruptcy mode.
+    /// Remaining accounts must hold a `[market, oracle]` pair for every market the user has a position in.
     ///
     /// # Arguments
     /// * `ctx` - The context for the instruction.
@@ -122,7 +390,409 @@ pub mod perp_dex {
         instructions::liquidation::handle_liquidate(ctx, market_index)
     }
 
-    /// Settles the funding rate payment for a user's position.
+    /// Recovers a market's pending ADL deficit by force-reducing profitable counterparties.
+    /// Remaining accounts are the user accounts of counterparties on the deleveraged side, in
+    /// descending ADL score order, each scoring at least an unleveraged position at the side's
+    /// average entry price.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market with the pending deficit.
+    pub fn auto_deleverage(ctx: Context<AutoDeleverage>, market_index: u16) -> Result<()> {
+        instructions::auto_deleverage::handle_auto_deleverage(ctx, market_index)
+    }
+
+    /// Creates the insurance fund vault.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `unstaking_period` - Seconds stakers must wait between requesting and completing an unstake.
+    pub fn initialize_insurance_fund(
+        ctx: Context<InitializeInsuranceFund>,
+        unstaking_period: i64,
+    ) -> Result<()> {
+        instructions::insurance_fund::handle_initialize_insurance_fund(ctx, unstaking_period)
+    }
+
+    /// Creates a staker's insurance fund stake account.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    pub fn initialize_insurance_fund_stake(ctx: Context<InitializeInsuranceFundStake>) -> Result<()> {
+        instructions::insurance_fund::handle_initialize_insurance_fund_stake(ctx)
+    }
+
+    /// Stakes collateral into the insurance fund in exchange for shares. Shares left outstanding
+    /// after deficits drained the vault to zero are voided first, and a balance the vault holds
+    /// while no shares are outstanding is issued to the protocol so the first staker cannot claim it.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `amount` - The amount of collateral to stake.
+    pub fn add_insurance_fund_stake(ctx: Context<AddInsuranceFundStake>, amount: u64) -> Result<()> {
+        instructions::insurance_fund::handle_add_insurance_fund_stake(ctx, amount)
+    }
+
+    /// Requests removal of insurance fund shares, starting the unstaking cooldown.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `shares` - The number of shares to remove.
+    pub fn request_remove_insurance_fund_stake(
+        ctx: Context<RequestRemoveInsuranceFundStake>,
+        shares: u128,
+    ) -> Result<()> {
+        instructions::insurance_fund::handle_request_remove_insurance_fund_stake(ctx, shares)
+    }
+
+    /// Completes a pending removal request once the unstaking period has elapsed.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    pub fn remove_insurance_fund_stake(ctx: Context<RemoveInsuranceFundStake>) -> Result<()> {
+        instructions::insurance_fund::handle_remove_insurance_fund_stake(ctx)
+    }
+
+    /// Moves a market's accrued insurance fees into the insurance fund.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to settle fees for.
+    pub fn settle_insurance_fee_pool(
+        ctx: Context<SettleInsuranceFeePool>,
+        market_index: u16,
+    ) -> Result<()> {
+        instructions::insurance_fund::handle_settle_insurance_fee_pool(ctx, market_index)
+    }
+
+    /// Liquidates by transferring the position onto the liquidator's user account at a discount
+    /// to the oracle price instead of closing it against the vAMM. A remainder left on a bankrupt
+    /// account is closed against the vAMM.
+    /// The liquidator must meet the initial margin requirement afterwards.
+    /// Remaining accounts must hold a `[market, oracle]` pair for every market either user has a position in.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to liquidate the position in.
+    /// * `max_base_asset_amount` - The most base the liquidator is willing to take over.
+    pub fn liquidate_perp_transfer(
+        ctx: Context<Liquidate>,
+        market_index: u16,
+        max_base_asset_amount: u128,
+    ) -> Result<()> {
+        instructions::liquidation::handle_liquidate_perp_transfer(ctx, market_index, max_base_asset_amount)
+    }
+
+    /// Creates the order book of an existing market.
+    /// Only callable by the program admin.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to create the order book for.
+    pub fn initialize_order_book(ctx: Context<InitializeOrderBook>, market_index: u16) -> Result<()> {
+        instructions::order_book::handle_initialize_order_book(ctx, market_index)
+    }
+
+    /// Places a limit order on a market's order book. Crossing resting orders are filled at
+    /// their price in price-time priority and the remainder rests on the book, locking margin.
+    /// Crossed orders of makers below maintenance margin or unable to cover the fill's loss are
+    /// cancelled instead of filled. The order must be at least the market's minimum order size,
+    /// and a remainder below it, of the order or of a partially filled maker's, is cancelled.
+    /// Remaining accounts must hold the `User` account of every crossed maker, followed by a
+    /// `[market, oracle]` pair for every market the user or those makers have a position or
+    /// orders in.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to trade.
+    /// * `base_asset_amount` - The amount of base asset to buy (positive) or sell (negative).
+    /// * `price` - The limit price.
+    /// * `order_type` - Whether the unfilled remainder rests (limit), is cancelled (IOC) or fails
+    ///   the order (FOK), or whether the order may only rest (post-only).
+    /// * `max_ts` - Optional positive timestamp after which the order is rejected and, once
+    ///   resting, no longer fills; expired resting orders are pruned when takers reach them or
+    ///   through `prune_expired_orders`.
+    pub fn place_order(
+        ctx: Context<PlaceOrder>,
+        market_index: u16,
+        base_asset_amount: i128,
+        price: u128,
+        order_type: OrderType,
+        max_ts: Option<i64>,
+    ) -> Result<()> {
+        instructions::order_book::handle_place_order(
+            ctx,
+            market_index,
+            base_asset_amount,
+            price,
+            order_type,
+            max_ts,
+        )
+    }
+
+    /// Cancels one of the user's resting orders.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market the order rests in.
+    /// * `order_id` - The id of the order to cancel.
+    pub fn cancel_order(ctx: Context<CancelOrder>, market_index: u16, order_id: u64) -> Result<()> {
+        instructions::order_book::handle_cancel_order(ctx, market_index, order_id)
+    }
+
+    /// Cancels all of the user's resting orders in a market.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to cancel orders in.
+    pub fn cancel_all_orders(ctx: Context<CancelOrder>, market_index: u16) -> Result<()> {
+        instructions::order_book::handle_cancel_all_orders(ctx, market_index)
+    }
+
+    /// Removes expired resting orders and limit orders in a market, releasing the margin they
+    /// locked. Permissionless.
+    /// Remaining accounts must hold the `User` account of every owner whose orders to prune.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to prune orders in.
+    pub fn prune_expired_orders(
+        ctx: Context<PruneExpiredOrders>,
+        market_index: u16,
+    ) -> Result<()> {
+        instructions::order_book::handle_prune_expired_orders(ctx, market_index)
+    }
+
+    /// Places a reduce-only stop-loss or take-profit order that a keeper executes once the
+    /// oracle or mark price crosses its trigger price. The user must hold a position in the
+    /// market, and the order only reduces that position's side.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `params` - The trigger order's market, trigger, size, limit price and optional expiry.
+    pub fn place_trigger_order(
+        ctx: Context<UpdateTriggerOrders>,
+        params: TriggerOrderParams,
+    ) -> Result<()> {
+        instructions::trigger_order::handle_place_trigger_order(ctx, params)
+    }
+
+    /// Cancels one of the user's trigger orders.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `order_index` - The trigger order slot to clear.
+    pub fn cancel_trigger_order(ctx: Context<UpdateTriggerOrders>, order_index: u8) -> Result<()> {
+        instructions::trigger_order::handle_cancel_trigger_order(ctx, order_index)
+    }
+
+    /// Executes a user's trigger order once its condition is met, reducing the position
+    /// against the vAMM. Permissionless.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market the order is in.
+    /// * `order_index` - The trigger order slot to execute.
+    pub fn trigger_order(
+        ctx: Context<ExecuteTriggerOrder>,
+        market_index: u16,
+        order_index: u8,
+    ) -> Result<()> {
+        instructions::trigger_order::handle_trigger_order(ctx, market_index, order_index)
+    }
+
+    /// Places a TWAP order that keepers work against the vAMM one slice per interval.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `params` - The TWAP order's market, total size, slice size, interval, limit price and
+    ///   optional positive timestamp after which no further slices execute.
+    pub fn place_twap_order(ctx: Context<UpdateTwapOrders>, params: TwapOrderParams) -> Result<()> {
+        instructions::twap_order::handle_place_twap_order(ctx, params)
+    }
+
+    /// Cancels one of the user's TWAP orders.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `order_index` - The TWAP order slot to clear.
+    pub fn cancel_twap_order(ctx: Context<UpdateTwapOrders>, order_index: u8) -> Result<()> {
+        instructions::twap_order::handle_cancel_twap_order(ctx, order_index)
+    }
+
+    /// Executes the next slice of a user's TWAP order against the vAMM once its interval has
+    /// elapsed, or clears it once past its expiry. Permissionless. Remaining accounts must hold a
+    /// `[market, oracle]` pair for every market the user has a position in.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market the order is in.
+    /// * `order_index` - The TWAP order slot to execute.
+    pub fn execute_twap_slice(
+        ctx: Context<ExecuteTwapSlice>,
+        market_index: u16,
+        order_index: u8,
+    ) -> Result<()> {
+        instructions::twap_order::handle_execute_twap_slice(ctx, market_index, order_index)
+    }
+
+    /// Places a resting limit order on the user account that keepers fill against the vAMM
+    /// once its price is reachable. The order locks margin until it fills or is cancelled.
+    /// Remaining accounts must hold a `[market, oracle]` pair for every market the user has
+    /// a position or orders in.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to trade.
+    /// * `base_asset_amount` - The amount of base asset to buy (positive) or sell (negative).
+    /// * `price` - The limit price.
+    /// * `max_ts` - Optional positive timestamp after which the order can no longer fill.
+    pub fn place_limit_order(
+        ctx: Context<PlaceLimitOrder>,
+        market_index: u16,
+        base_asset_amount: i128,
+        price: u128,
+        max_ts: Option<i64>,
+    ) -> Result<()> {
+        instructions::limit_order::handle_place_limit_order(
+            ctx,
+            market_index,
+            base_asset_amount,
+            price,
+            max_ts,
+        )
+    }
+
+    /// Changes the size and price of one of the user's limit orders.
+    /// Remaining accounts are the `[market, oracle]` pairs for the margin check.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `order_id` - The id of the order to modify.
+    /// * `base_asset_amount` - The new unfilled base asset amount.
+    /// * `price` - The new limit price.
+    pub fn modify_limit_order(
+        ctx: Context<UpdateLimitOrders>,
+        order_id: u64,
+        base_asset_amount: u128,
+        price: u128,
+    ) -> Result<()> {
+        instructions::limit_order::handle_modify_limit_order(ctx, order_id, base_asset_amount, price)
+    }
+
+    /// Cancels one of the user's limit orders, releasing the margin it locked.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `order_id` - The id of the order to cancel.
+    pub fn cancel_limit_order(ctx: Context<UpdateLimitOrders>, order_id: u64) -> Result<()> {
+        instructions::limit_order::handle_cancel_limit_order(ctx, order_id)
+    }
+
+    /// Fills a user's limit order against the vAMM as far as its limit price allows, paying
+    /// the keeper a share of the fill's notional from the order owner's collateral. A partial
+    /// fill must be at least the market's minimum order size. Permissionless.
+    /// Remaining accounts are the owner's `[market, oracle]` pairs for the margin check.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market the order is in.
+    /// * `order_id` - The id of the order to fill.
+    pub fn fill_limit_order(
+        ctx: Context<FillLimitOrder>,
+        market_index: u16,
+        order_id: u64,
+    ) -> Result<()> {
+        instructions::limit_order::handle_fill_limit_order(ctx, market_index, order_id)
+    }
+
+    /// Registers or unregisters the user as a JIT maker allowed to fill taker auctions.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `is_jit_maker` - Whether the user may fill taker auctions.
+    pub fn register_jit_maker(ctx: Context<RegisterJitMaker>, is_jit_maker: bool) -> Result<()> {
+        instructions::taker_auction::handle_register_jit_maker(ctx, is_jit_maker)
+    }
+
+    /// Records a trade intent that registered JIT makers can fill for a short auction window
+    /// before the remainder settles against the vAMM.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to trade.
+    /// * `base_asset_amount` - The amount of base asset to buy (positive) or sell (negative).
+    /// * `limit_price` - The worst acceptable price from makers and the vAMM.
+    /// * `max_ts` - Optional positive timestamp after which the auction no longer fills and
+    ///   settles without trading.
+    pub fn begin_taker_auction(
+        ctx: Context<BeginTakerAuction>,
+        market_index: u16,
+        base_asset_amount: i128,
+        limit_price: u128,
+        max_ts: Option<i64>,
+    ) -> Result<()> {
+        instructions::taker_auction::handle_begin_taker_auction(
+            ctx,
+            market_index,
+            base_asset_amount,
+            limit_price,
+            max_ts,
+        )
+    }
+
+    /// Closes the taker's auction without trading its unfilled remainder.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    pub fn cancel_taker_auction(ctx: Context<CancelTakerAuction>) -> Result<()> {
+        instructions::taker_auction::handle_cancel_taker_auction(ctx)
+    }
+
+    /// Fills part of a taker auction as a registered JIT maker at a price at least as good
+    /// as the vAMM quote for the same size and within the market's oracle band.
+    /// Both users must meet the initial margin requirement afterwards.
+    /// Remaining accounts must hold a `[market, oracle]` pair for every market either user has a position in.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `base_asset_amount` - The amount of base asset to fill.
+    /// * `price` - The fill price.
+    pub fn fill_taker_auction(
+        ctx: Context<FillTakerAuction>,
+        base_asset_amount: u128,
+        price: u128,
+    ) -> Result<()> {
+        instructions::taker_auction::handle_fill_taker_auction(ctx, base_asset_amount, price)
+    }
+
+    /// Settles the unfilled remainder of a taker auction against the vAMM once the auction
+    /// window has passed or the auction has expired, and closes the auction. The remainder is
+    /// dropped without trading if the auction has expired, the market is paused, the taker's
+    /// margin could not hold it or the vAMM cannot fill it within the limit price and the
+    /// oracle band. Permissionless.
+    /// Remaining accounts must hold a `[market, oracle]` pair for every market the taker has a position in.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    pub fn settle_taker_auction(ctx: Context<SettleTakerAuction>) -> Result<()> {
+        instructions::taker_auction::handle_settle_taker_auction(ctx)
+    }
+
+    /// Computes the market's funding rate for the elapsed period and accrues it into the
+    /// cumulative funding rates. Any imbalance between longs and shorts is absorbed by the
+    /// fee pool, scaling down the receiving side once it is exhausted.
+    /// Permissionless; callable once per `funding_period`.
+    ///
+    /// # Arguments
+    /// * `ctx` - The context for the instruction.
+    /// * `market_index` - The index of the market to update funding for.
+    pub fn update_funding_rate(ctx: Context<UpdateFundingRate>, market_index: u16) -> Result<()> {
+        instructions::funding::handle_update_funding_rate(ctx, market_index)
+    }
+
+    /// Settles the funding a user's position accrued since it last settled.
     ///
     /// # Arguments
     /// * `ctx` - The context for the instruction.
@@ -1,4 +1,7 @@
 use anchor_lang::prelude::*;
+use crate::state::constants::{
+    MAX_INVENTORY_SPREAD_SCALE, PEG_PRECISION, PRECISION, RATIO_PRECISION,
+};
 use crate::error::PerpError;
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -38,3 +41,301 @@ pub fn calculate_swap_output(
 
     Ok((new_quote_asset_reserve, new_base_asset_reserve))
 }
+
+/// Converts a change in the quote asset reserve into the quote asset amount it is worth at
+/// `peg_multiplier`.
+pub fn calculate_quote_asset_amount(
+    quote_asset_reserve_delta: u128,
+    peg_multiplier: u128,
+) -> Result<u128> {
+    quote_asset_reserve_delta
+        .checked_mul(peg_multiplier)
+        .and_then(|q| q.checked_div(PEG_PRECISION))
+        .ok_or(PerpError::MathOverflow.into())
+}
+
+/// Calculates the largest base asset amount that can be swapped against the vAMM with an
+/// average price no worse than `limit_price`. Buying `b` base averages `Q * peg / (B - b)` and
+/// selling it averages `Q * peg / (B + b)`, so the bound follows directly from the reserves.
+pub fn calculate_max_base_asset_amount_within_limit(
+    base_asset_reserve: u128,
+    quote_asset_reserve: u128,
+    peg_multiplier: u128,
+    limit_price: u128,
+    direction: TradeDirection,
+) -> Result<u128> {
+    if limit_price == 0 {
+        return Ok(match direction {
+            TradeDirection::Long => 0,
+            TradeDirection::Short => u128::MAX,
+        });
+    }
+
+    let scaled_quote_asset_reserve = quote_asset_reserve
+        .checked_mul(PRECISION)
+        .and_then(|q| q.checked_mul(peg_multiplier))
+        .ok_or(PerpError::MathOverflow)?;
+    let limit_price = limit_price
+        .checked_mul(PEG_PRECISION)
+        .ok_or(PerpError::MathOverflow)?;
+
+    // Base reserve at which the average price reaches the limit, rounded against the trader
+    Ok(match direction {
+        TradeDirection::Long => {
+            let min_base_asset_reserve = scaled_quote_asset_reserve
+                .checked_add(limit_price - 1)
+                .and_then(|r| r.checked_div(limit_price))
+                .ok_or(PerpError::MathOverflow)?;
+            base_asset_reserve.saturating_sub(min_base_asset_reserve)
+        }
+        TradeDirection::Short => {
+            let max_base_asset_reserve = scaled_quote_asset_reserve
+                .checked_div(limit_price)
+                .ok_or(PerpError::MathOverflow)?;
+            max_base_asset_reserve.saturating_sub(base_asset_reserve)
+        }
+    })
+}
+
+/// Calculates the (long, short) spreads (scaled by 1_000_000) charged around the curve price.
+/// Both sides pay `base_spread` plus the oracle's confidence interval as a share of its price.
+/// The side pushing the mark further from the oracle also pays the mark-oracle TWAP divergence,
+/// and the side growing the users' net inventory pays up to `MAX_INVENTORY_SPREAD_SCALE` times
+/// the base spread more as the skew approaches the whole base reserve. Each side is capped at
+/// `max_spread`.
+pub fn calculate_spreads(
+    base_spread: u64,
+    max_spread: u64,
+    net_user_base_asset_amount: i128,
+    base_asset_reserve: u128,
+    oracle_confidence: u128,
+    oracle_price_twap: u128,
+    mark_price_twap: u128,
+) -> Result<(u64, u64)> {
+    let ratio_precision = RATIO_PRECISION as u128;
+    let base_spread = base_spread as u128;
+
+    let (confidence_spread, divergence_spread) = if oracle_price_twap == 0 {
+        (0, 0)
+    } else {
+        let to_ratio = |amount: u128| {
+            amount
+                .checked_mul(ratio_precision)
+                .and_then(|r| r.checked_div(oracle_price_twap))
+                .ok_or(PerpError::MathOverflow)
+        };
+        (
+            to_ratio(oracle_confidence)?,
+            to_ratio(mark_price_twap.abs_diff(oracle_price_twap))?,
+        )
+    };
+
+    let skew = if base_asset_reserve == 0 {
+        ratio_precision
+    } else {
+        net_user_base_asset_amount
+            .unsigned_abs()
+            .checked_mul(ratio_precision)
+            .and_then(|s| s.checked_div(base_asset_reserve))
+            .ok_or(PerpError::MathOverflow)?
+            .min(ratio_precision)
+    };
+    let inventory_spread = base_spread
+        .checked_mul(skew)
+        .and_then(|s| s.checked_mul(MAX_INVENTORY_SPREAD_SCALE))
+        .and_then(|s| s.checked_div(ratio_precision))
+        .ok_or(PerpError::MathOverflow)?;
+
+    let side_spread = |is_long: bool| -> Result<u64> {
+        let mut spread = base_spread
+            .checked_add(confidence_spread)
+            .ok_or(PerpError::MathOverflow)?;
+        if (mark_price_twap > oracle_price_twap) == is_long && mark_price_twap != oracle_price_twap {
+            spread = spread
+                .checked_add(divergence_spread)
+                .ok_or(PerpError::MathOverflow)?;
+        }
+        if (net_user_base_asset_amount > 0) == is_long && net_user_base_asset_amount != 0 {
+            spread = spread
+                .checked_add(inventory_spread)
+                .ok_or(PerpError::MathOverflow)?;
+        }
+        Ok(spread.min(max_spread as u128) as u64)
+    };
+
+    Ok((side_spread(true)?, side_spread(false)?))
+}
+
+/// Calculates what moving the peg from `old_peg_multiplier` to `new_peg_multiplier` costs the
+/// vAMM (negative for a profit), given the users' net base position against it. Net user longs
+/// would sell their base back into the vAMM, so a higher peg pays them more; net user shorts
+/// would buy it back, so a higher peg charges them more. Returned in price precision.
+pub fn calculate_repeg_cost(
+    base_asset_reserve: u128,
+    quote_asset_reserve: u128,
+    net_user_base_asset_amount: i128,
+    old_peg_multiplier: u128,
+    new_peg_multiplier: u128,
+) -> Result<i128> {
+    let quote_asset_reserve_delta = calculate_terminal_quote_asset_reserve_delta(
+        base_asset_reserve,
+        quote_asset_reserve,
+        net_user_base_asset_amount,
+    )? as i128;
+
+    let peg_delta = new_peg_multiplier as i128 - old_peg_multiplier as i128;
+    let cost = quote_asset_reserve_delta
+        .checked_mul(peg_delta)
+        .and_then(|c| c.checked_div(PEG_PRECISION as i128))
+        .ok_or(PerpError::MathOverflow)?;
+
+    Ok(if net_user_base_asset_amount > 0 {
+        cost
+    } else {
+        -cost
+    })
+}
+
+/// Calculates what scaling the reserves to `new_base_asset_reserve` and `new_quote_asset_reserve`
+/// costs the vAMM (negative for a profit), given the users' net base position against it.
+/// Deeper reserves let net user longs sell back for more and net user shorts buy back for less.
+/// Returned in price precision.
+pub fn calculate_k_update_cost(
+    base_asset_reserve: u128,
+    quote_asset_reserve: u128,
+    new_base_asset_reserve: u128,
+    new_quote_asset_reserve: u128,
+    net_user_base_asset_amount: i128,
+    peg_multiplier: u128,
+) -> Result<i128> {
+    if net_user_base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    let quote_asset_amount = calculate_quote_asset_amount(
+        calculate_terminal_quote_asset_reserve_delta(
+            base_asset_reserve,
+            quote_asset_reserve,
+            net_user_base_asset_amount,
+        )?,
+        peg_multiplier,
+    )? as i128;
+    let new_quote_asset_amount = calculate_quote_asset_amount(
+        calculate_terminal_quote_asset_reserve_delta(
+            new_base_asset_reserve,
+            new_quote_asset_reserve,
+            net_user_base_asset_amount,
+        )?,
+        peg_multiplier,
+    )? as i128;
+
+    let cost = new_quote_asset_amount - quote_asset_amount;
+    Ok(if net_user_base_asset_amount > 0 {
+        cost
+    } else {
+        -cost
+    })
+}
+
+/// Calculates how far the quote asset reserve would move if users closed their net base
+/// position against the vAMM.
+fn calculate_terminal_quote_asset_reserve_delta(
+    base_asset_reserve: u128,
+    quote_asset_reserve: u128,
+    net_user_base_asset_amount: i128,
+) -> Result<u128> {
+    if net_user_base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    let direction = if net_user_base_asset_amount > 0 {
+        TradeDirection::Short
+    } else {
+        TradeDirection::Long
+    };
+    let (new_quote_asset_reserve, _) = calculate_swap_output(
+        net_user_base_asset_amount.unsigned_abs(),
+        base_asset_reserve,
+        quote_asset_reserve,
+        direction,
+    )?;
+
+    Ok(quote_asset_reserve.abs_diff(new_quote_asset_reserve))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeg_cost_follows_the_users_net_position() {
+        let reserve = 1_000 * PRECISION;
+        let peg = 100 * PEG_PRECISION;
+
+        // Net longs would sell 10 base back, moving the quote reserve by ~9.9
+        assert_eq!(
+            calculate_repeg_cost(reserve, reserve, 10 * PRECISION as i128, peg, peg + PEG_PRECISION).unwrap(),
+            9_900_990_100
+        );
+        assert_eq!(
+            calculate_repeg_cost(reserve, reserve, 10 * PRECISION as i128, peg, peg - PEG_PRECISION).unwrap(),
+            -9_900_990_100
+        );
+        // Net shorts would buy 10 base back, so a higher peg is a profit
+        assert_eq!(
+            calculate_repeg_cost(reserve, reserve, -10 * PRECISION as i128, peg, peg + PEG_PRECISION).unwrap(),
+            -10_101_010_101
+        );
+        assert_eq!(calculate_repeg_cost(reserve, reserve, 0, peg, peg + PEG_PRECISION).unwrap(), 0);
+    }
+
+    #[test]
+    fn deeper_reserves_cost_the_vamm_when_users_are_long() {
+        let reserve = 1_000 * PRECISION;
+        let peg = 100 * PEG_PRECISION;
+
+        // Doubling the reserves lets net longs sell 10 base back for ~4.93 more
+        assert_eq!(
+            calculate_k_update_cost(reserve, reserve, 2 * reserve, 2 * reserve, 10 * PRECISION as i128, peg).unwrap(),
+            4_925_865_700
+        );
+        // and net shorts buy it back for ~5.08 less
+        assert_eq!(
+            calculate_k_update_cost(reserve, reserve, 2 * reserve, 2 * reserve, -10 * PRECISION as i128, peg).unwrap(),
+            -5_075_884_500
+        );
+        assert_eq!(calculate_k_update_cost(reserve, reserve, 2 * reserve, 2 * reserve, 0, peg).unwrap(), 0);
+    }
+
+    #[test]
+    fn spreads_widen_with_confidence_divergence_and_inventory() {
+        let reserve = 1_000 * PRECISION;
+        let twap = 100 * PRECISION;
+
+        assert_eq!(calculate_spreads(1_000, 100_000, 0, reserve, 0, 0, 0).unwrap(), (1_000, 1_000));
+        // A confidence of 0.5% widens both sides
+        assert_eq!(
+            calculate_spreads(1_000, 100_000, 0, reserve, PRECISION / 2, twap, twap).unwrap(),
+            (6_000, 6_000)
+        );
+        // A mark TWAP 2% over the oracle TWAP widens the ask only
+        assert_eq!(
+            calculate_spreads(1_000, 100_000, 0, reserve, 0, twap, 102 * PRECISION).unwrap(),
+            (21_000, 1_000)
+        );
+        // Users net long 1% of the reserve pay up to 10x the base spread scaled by the skew
+        assert_eq!(
+            calculate_spreads(1_000, 100_000, 10 * PRECISION as i128, reserve, 0, twap, twap).unwrap(),
+            (1_100, 1_000)
+        );
+        assert_eq!(
+            calculate_spreads(1_000, 100_000, -10 * PRECISION as i128, reserve, 0, twap, twap).unwrap(),
+            (1_000, 1_100)
+        );
+        // Each side is capped
+        assert_eq!(
+            calculate_spreads(1_000, 5_000, 0, reserve, 0, twap, 102 * PRECISION).unwrap(),
+            (5_000, 1_000)
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::PRECISION;
+use crate::error::PerpError;
+
+/// Calculates the loss per unit of base (in price precision) when `deficit` is spread over `open_interest`.
+/// Rounds up so the full deficit is always charged.
+pub fn calculate_loss_per_base(deficit: u128, open_interest: u128) -> Result<u128> {
+    let numerator = deficit
+        .checked_mul(PRECISION)
+        .ok_or(PerpError::MathOverflow)?;
+
+    numerator
+        .checked_add(open_interest - 1)
+        .and_then(|n| n.checked_div(open_interest))
+        .ok_or(PerpError::MathOverflow.into())
+}
+
+/// Ranks a profitable position for auto-deleveraging as PnL percentage times leverage.
+/// Higher scores are deleveraged first.
+pub fn calculate_adl_score(
+    unrealized_pnl: u128,
+    entry_value: u128,
+    notional: u128,
+    collateral: u128,
+) -> Result<u128> {
+    if entry_value == 0 || collateral == 0 {
+        return Ok(u128::MAX);
+    }
+
+    let pnl_ratio = unrealized_pnl
+        .checked_mul(PRECISION)
+        .and_then(|r| r.checked_div(entry_value))
+        .ok_or(PerpError::MathOverflow)?;
+    let leverage = notional
+        .checked_mul(PRECISION)
+        .and_then(|l| l.checked_div(collateral))
+        .ok_or(PerpError::MathOverflow)?;
+
+    pnl_ratio
+        .checked_mul(leverage)
+        .and_then(|s| s.checked_div(PRECISION))
+        .ok_or(PerpError::MathOverflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loss_per_base_rounds_up() {
+        assert_eq!(calculate_loss_per_base(PRECISION, 3 * PRECISION).unwrap(), 333_333_334);
+        assert_eq!(calculate_loss_per_base(PRECISION, 4 * PRECISION).unwrap(), 250_000_000);
+    }
+
+    #[test]
+    fn adl_score_ranks_pnl_ratio_by_leverage() {
+        // Up 10% at 10x leverage
+        let leveraged = calculate_adl_score(10 * PRECISION, 100 * PRECISION, 110 * PRECISION, 11 * PRECISION).unwrap();
+        // Up 10% unleveraged
+        let unleveraged = calculate_adl_score(10 * PRECISION, 100 * PRECISION, 110 * PRECISION, 110 * PRECISION).unwrap();
+
+        assert_eq!(leveraged, PRECISION);
+        assert_eq!(unleveraged, PRECISION / 10);
+        assert_eq!(calculate_adl_score(10 * PRECISION, 100 * PRECISION, 110 * PRECISION, 0).unwrap(), u128::MAX);
+    }
+}
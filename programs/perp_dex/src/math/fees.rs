@@ -0,0 +1,89 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{LIMIT_ORDER_FILLER_REWARD_RATE, RATIO_PRECISION};
+use crate::math::margin::{quote_to_collateral, quote_to_collateral_round_up};
+use crate::error::PerpError;
+
+/// Calculates the fee on a fill's quote notional (price precision).
+/// Returns the fee in collateral precision.
+pub fn calculate_trade_fee(quote_asset_amount: u128, trade_fee_rate: u64) -> Result<u64> {
+    calculate_fee(quote_asset_amount, trade_fee_rate)
+}
+
+/// Calculates the fee on a liquidated position's quote notional (price precision).
+/// Returns the fee in collateral precision.
+pub fn calculate_liquidation_fee(quote_asset_amount: u128, liquidation_fee_rate: u64) -> Result<u64> {
+    calculate_fee(quote_asset_amount, liquidation_fee_rate)
+}
+
+/// Calculates the reward a limit order's owner pays the keeper filling `quote_asset_amount`
+/// (price precision) of it, rounding down. Returns the reward in collateral precision.
+pub fn calculate_filler_reward(quote_asset_amount: u128) -> Result<u64> {
+    let reward = quote_asset_amount
+        .checked_mul(LIMIT_ORDER_FILLER_REWARD_RATE as u128)
+        .and_then(|r| r.checked_div(RATIO_PRECISION as u128))
+        .ok_or(PerpError::MathOverflow)?;
+
+    quote_to_collateral(reward)
+}
+
+/// Splits a liquidation fee into the liquidator's share and the insurance fund's remainder.
+pub fn split_liquidation_fee(liquidation_fee: u64, liquidator_fee_share: u64) -> Result<(u64, u64)> {
+    let liquidator_fee = (liquidation_fee as u128)
+        .checked_mul(liquidator_fee_share as u128)
+        .and_then(|f| f.checked_div(RATIO_PRECISION as u128))
+        .ok_or(PerpError::MathOverflow)? as u64;
+    let insurance_fee = liquidation_fee
+        .checked_sub(liquidator_fee)
+        .ok_or(PerpError::MathOverflow)?;
+
+    Ok((liquidator_fee, insurance_fee))
+}
+
+/// Rounds up so a fill too small to owe a whole collateral unit still pays one.
+fn calculate_fee(quote_asset_amount: u128, fee_rate: u64) -> Result<u64> {
+    let fee = quote_asset_amount
+        .checked_mul(fee_rate as u128)
+        .and_then(|f| f.checked_add(RATIO_PRECISION as u128 - 1))
+        .and_then(|f| f.checked_div(RATIO_PRECISION as u128))
+        .ok_or(PerpError::MathOverflow)?;
+
+    quote_to_collateral_round_up(fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::constants::PRECISION;
+
+    #[test]
+    fn trade_fee_is_charged_on_notional() {
+        // 0.1% of 100
+        assert_eq!(calculate_trade_fee(100 * PRECISION, 1_000).unwrap(), 100_000);
+    }
+
+    #[test]
+    fn trade_fee_rounds_up() {
+        assert_eq!(calculate_trade_fee(1, 1_000).unwrap(), 1);
+        assert_eq!(calculate_trade_fee(100 * PRECISION + 1, 1_000).unwrap(), 100_001);
+        assert_eq!(calculate_trade_fee(100 * PRECISION, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn liquidation_fee_splits_between_liquidator_and_insurance() {
+        let liquidation_fee = calculate_liquidation_fee(500 * PRECISION, 10_000).unwrap();
+        assert_eq!(liquidation_fee, 5_000_000);
+
+        // The liquidator's share rounds down, the remainder goes to insurance
+        assert_eq!(split_liquidation_fee(1_001, 500_000).unwrap(), (500, 501));
+        assert_eq!(split_liquidation_fee(liquidation_fee, 1_000_000).unwrap(), (5_000_000, 0));
+    }
+
+    #[test]
+    fn filler_reward_is_a_share_of_the_fill_notional_rounded_down() {
+        // 0.01% of 10_000
+        assert_eq!(calculate_filler_reward(10_000 * PRECISION).unwrap(), 1_000_000);
+        assert_eq!(calculate_filler_reward(9_999 * PRECISION).unwrap(), 999_900);
+        // Less than a collateral unit pays nothing
+        assert_eq!(calculate_filler_reward(9 * PRECISION / 1_000).unwrap(), 0);
+    }
+}
@@ -0,0 +1,174 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{ONE_DAY, PRECISION, RATIO_PRECISION};
+use crate::error::PerpError;
+
+/// Calculates the funding rate for one `funding_period` from the mark and oracle TWAPs:
+/// the premium scaled from a daily rate to the period, clamped to `max_funding_rate` of the oracle TWAP.
+/// Expressed as the payment per unit of base (in price precision), positive when longs pay shorts.
+pub fn calculate_funding_rate(
+    mark_price_twap: u128,
+    oracle_price_twap: u128,
+    funding_period: i64,
+    max_funding_rate: u64,
+) -> Result<i128> {
+    let premium = (mark_price_twap as i128)
+        .checked_sub(oracle_price_twap as i128)
+        .ok_or(PerpError::MathOverflow)?;
+
+    let funding_rate = premium
+        .checked_mul(funding_period as i128)
+        .and_then(|r| r.checked_div(ONE_DAY as i128))
+        .ok_or(PerpError::MathOverflow)?;
+
+    let max_funding_rate = oracle_price_twap
+        .checked_mul(max_funding_rate as u128)
+        .and_then(|r| r.checked_div(RATIO_PRECISION as u128))
+        .ok_or(PerpError::MathOverflow)? as i128;
+
+    Ok(funding_rate.clamp(-max_funding_rate, max_funding_rate))
+}
+
+/// Per-side funding rates and totals for one funding update.
+pub struct FundingPayments {
+    /// Funding rate accrued by longs (in price precision).
+    pub funding_rate_long: i128,
+
+    /// Funding rate accrued by shorts (in price precision).
+    pub funding_rate_short: i128,
+
+    /// Funding charged to the paying side (in price precision).
+    pub funding_paid: u128,
+
+    /// Funding credited to the receiving side (in price precision).
+    pub funding_received: u128,
+}
+
+/// Splits `funding_rate` into per-side rates. The paying side is charged the full rate and the
+/// receiving side is credited at most what is paid in plus `budget`, with its rate scaled down
+/// when the imbalance exceeds the budget.
+pub fn calculate_funding_payments(
+    funding_rate: i128,
+    base_asset_amount_long: u128,
+    base_asset_amount_short: u128,
+    budget: u128,
+) -> Result<FundingPayments> {
+    let (payer_base, receiver_base) = if funding_rate > 0 {
+        (base_asset_amount_long, base_asset_amount_short)
+    } else {
+        (base_asset_amount_short, base_asset_amount_long)
+    };
+
+    let rate = funding_rate.unsigned_abs();
+    let funding_paid = payer_base
+        .checked_mul(rate)
+        .and_then(|p| p.checked_div(PRECISION))
+        .ok_or(PerpError::MathOverflow)?;
+    let funding_owed = receiver_base
+        .checked_mul(rate)
+        .and_then(|p| p.checked_div(PRECISION))
+        .ok_or(PerpError::MathOverflow)?;
+    let funding_received = funding_owed.min(funding_paid.saturating_add(budget));
+
+    let receiver_rate = if funding_received == funding_owed {
+        rate
+    } else {
+        funding_received
+            .checked_mul(PRECISION)
+            .and_then(|r| r.checked_div(receiver_base))
+            .ok_or(PerpError::MathOverflow)?
+    } as i128;
+    let receiver_rate = if funding_rate > 0 { receiver_rate } else { -receiver_rate };
+
+    let (funding_rate_long, funding_rate_short) = if funding_rate > 0 {
+        (funding_rate, receiver_rate)
+    } else {
+        (receiver_rate, funding_rate)
+    };
+
+    Ok(FundingPayments {
+        funding_rate_long,
+        funding_rate_short,
+        funding_paid,
+        funding_received,
+    })
+}
+
+/// Blends `price` into a TWAP over a window of `period` seconds, weighting it by the time
+/// elapsed since the last update. The first observation seeds the TWAP.
+pub fn calculate_new_twap(
+    last_twap: u128,
+    last_twap_ts: i64,
+    price: u128,
+    now: i64,
+    period: i64,
+) -> Result<u128> {
+    if last_twap_ts == 0 {
+        return Ok(price);
+    }
+
+    let since_last = now.saturating_sub(last_twap_ts).clamp(0, period) as u128;
+    let from_start = (period as u128)
+        .checked_sub(since_last)
+        .ok_or(PerpError::MathOverflow)?;
+
+    last_twap
+        .checked_mul(from_start)
+        .and_then(|t| t.checked_add(price.checked_mul(since_last)?))
+        .and_then(|t| t.checked_div(period as u128))
+        .ok_or(PerpError::MathOverflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn funding_rate_scales_the_daily_premium_to_the_period() {
+        // A premium of 2.4 a day is 0.1 an hour
+        let funding_rate =
+            calculate_funding_rate(102_400_000_000, 100 * PRECISION, 3_600, 1_000_000).unwrap();
+        assert_eq!(funding_rate, 100_000_000);
+
+        let funding_rate =
+            calculate_funding_rate(97_600_000_000, 100 * PRECISION, 3_600, 1_000_000).unwrap();
+        assert_eq!(funding_rate, -100_000_000);
+    }
+
+    #[test]
+    fn funding_rate_is_clamped_to_the_oracle_twap() {
+        // 0.05% of 100 per period
+        let funding_rate =
+            calculate_funding_rate(200 * PRECISION, 100 * PRECISION, 3_600, 500).unwrap();
+        assert_eq!(funding_rate, 50_000_000);
+
+        let funding_rate = calculate_funding_rate(0, 100 * PRECISION, 3_600, 500).unwrap();
+        assert_eq!(funding_rate, -50_000_000);
+    }
+
+    #[test]
+    fn twap_weights_prices_by_time_held() {
+        assert_eq!(calculate_new_twap(0, 0, 100, 1_000, 3_600).unwrap(), 100);
+        // A quarter of the window at 200
+        assert_eq!(calculate_new_twap(100, 1_000, 200, 1_900, 3_600).unwrap(), 125);
+        // A full window replaces the TWAP
+        assert_eq!(calculate_new_twap(100, 1_000, 200, 10_000, 3_600).unwrap(), 200);
+        // No time elapsed leaves it unchanged
+        assert_eq!(calculate_new_twap(100, 1_000, 200, 1_000, 3_600).unwrap(), 100);
+    }
+
+    #[test]
+    fn funding_payouts_are_capped_at_receipts_plus_budget() {
+        // Longs pay 1 per base on 1 base while 3 short base are owed 3; shorts receive on a
+        // positive rate as their base is negative
+        let payments = calculate_funding_payments(PRECISION as i128, PRECISION, 3 * PRECISION, PRECISION).unwrap();
+        assert_eq!(payments.funding_paid, PRECISION);
+        assert_eq!(payments.funding_received, 2 * PRECISION);
+        assert_eq!(payments.funding_rate_long, PRECISION as i128);
+        assert_eq!(payments.funding_rate_short, 666_666_666);
+
+        // A budget covering the imbalance pays the full rate
+        let payments = calculate_funding_payments(PRECISION as i128, PRECISION, 3 * PRECISION, 5 * PRECISION).unwrap();
+        assert_eq!(payments.funding_received, 3 * PRECISION);
+        assert_eq!(payments.funding_rate_short, PRECISION as i128);
+    }
+}
@@ -0,0 +1,85 @@
+use anchor_lang::prelude::*;
+use crate::error::PerpError;
+
+/// Calculates the shares minted for staking `amount` into a vault holding `vault_balance`.
+/// The first stake into an empty vault mints shares one-to-one. A balance already in the vault
+/// must be backed by protocol shares first, and shares outstanding against a drained vault must
+/// be voided first, as they cannot price new ones.
+pub fn amount_to_shares(amount: u64, total_shares: u128, vault_balance: u64) -> Result<u128> {
+    if total_shares == 0 {
+        return Ok(amount as u128);
+    }
+    require_gt!(vault_balance, 0, PerpError::InsuranceFundDepleted);
+
+    (amount as u128)
+        .checked_mul(total_shares)
+        .and_then(|s| s.checked_div(vault_balance as u128))
+        .ok_or(PerpError::MathOverflow.into())
+}
+
+/// Calculates the vault amount redeemable for `shares`, rounding down.
+pub fn shares_to_amount(shares: u128, total_shares: u128, vault_balance: u64) -> Result<u64> {
+    if total_shares == 0 {
+        return Ok(0);
+    }
+
+    let amount = shares
+        .checked_mul(vault_balance as u128)
+        .and_then(|a| a.checked_div(total_shares))
+        .ok_or(PerpError::MathOverflow)?;
+    u64::try_from(amount).map_err(|_| PerpError::MathOverflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::state::InsuranceFund;
+
+    #[test]
+    fn first_stake_is_priced_against_the_existing_balance() {
+        let mut insurance_fund = InsuranceFund::default();
+        let vault_balance = 1_000_000;
+
+        insurance_fund.seed_protocol_shares(vault_balance);
+        let shares = amount_to_shares(1_000_000, insurance_fund.total_shares, vault_balance).unwrap();
+        insurance_fund.total_shares += shares;
+
+        assert_eq!(insurance_fund.protocol_shares, 1_000_000);
+        assert_eq!(shares, 1_000_000);
+        // The staker can only redeem what they put in
+        assert_eq!(shares_to_amount(shares, insurance_fund.total_shares, 2_000_000).unwrap(), 1_000_000);
+    }
+
+    #[test]
+    fn first_stake_into_an_empty_vault_mints_one_to_one() {
+        let mut insurance_fund = InsuranceFund::default();
+        insurance_fund.seed_protocol_shares(0);
+
+        assert_eq!(insurance_fund.total_shares, 0);
+        assert_eq!(amount_to_shares(5_000_000, 0, 0).unwrap(), 5_000_000);
+    }
+
+    #[test]
+    fn unstaking_shares_losses_and_gains() {
+        let total_shares = 3_000_000;
+        // The vault covered a deficit of a third of its balance
+        assert_eq!(shares_to_amount(1_000_000, total_shares, 2_000_000).unwrap(), 666_666);
+        // Fees grew the vault by half
+        assert_eq!(shares_to_amount(1_000_000, total_shares, 4_500_000).unwrap(), 1_500_000);
+    }
+
+    #[test]
+    fn depleted_vault_voids_all_shares() {
+        let mut insurance_fund = InsuranceFund {
+            total_shares: 3_000_000,
+            protocol_shares: 1_000_000,
+            ..Default::default()
+        };
+        assert!(amount_to_shares(1_000_000, insurance_fund.total_shares, 0).is_err());
+
+        insurance_fund.rebase_if_depleted(0).unwrap();
+        assert_eq!(insurance_fund.total_shares, 0);
+        assert_eq!(insurance_fund.protocol_shares, 0);
+        assert_eq!(insurance_fund.shares_epoch, 1);
+    }
+}
@@ -0,0 +1,99 @@
+use anchor_lang::prelude::*;
+use crate::state::constants::{PRECISION, RATIO_PRECISION};
+use crate::error::PerpError;
+
+/// Calculates the base asset amount to liquidate to cover `margin_shortage` (in price precision).
+/// Each unit closed at `oracle_price` frees `target_margin_ratio` of its notional and costs
+/// `liquidation_fee_rate` of it. Returns `u128::MAX` when closing cannot improve the margin,
+/// meaning the whole position should be liquidated.
+pub fn calculate_base_asset_amount_to_liquidate(
+    margin_shortage: u128,
+    oracle_price: u128,
+    target_margin_ratio: u64,
+    liquidation_fee_rate: u64,
+) -> Result<u128> {
+    if target_margin_ratio <= liquidation_fee_rate || oracle_price == 0 {
+        return Ok(u128::MAX);
+    }
+    let freed_margin_ratio = (target_margin_ratio - liquidation_fee_rate) as u128;
+
+    let numerator = margin_shortage
+        .checked_mul(PRECISION)
+        .and_then(|n| n.checked_mul(RATIO_PRECISION as u128))
+        .ok_or(PerpError::MathOverflow)?;
+    let denominator = oracle_price
+        .checked_mul(freed_margin_ratio)
+        .ok_or(PerpError::MathOverflow)?;
+
+    // Round up so the liquidation never falls short of the target
+    numerator
+        .checked_add(denominator - 1)
+        .and_then(|n| n.checked_div(denominator))
+        .ok_or(PerpError::MathOverflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+    use crate::math::fees::calculate_liquidation_fee;
+    use crate::math::margin::{is_liquidatable, MarginRequirementType, MarketOracle, MarketOracleMap};
+    use crate::state::market::Market;
+    use crate::state::user::User;
+
+    #[test]
+    fn partial_liquidation_restores_the_target_margin() {
+        let market = Market {
+            maintenance_margin_ratio: 50_000,
+            liquidation_margin_buffer_ratio: 20_000,
+            liquidation_fee_rate: 10_000,
+            ..Default::default()
+        };
+        let oracle_price = 100 * PRECISION;
+        let mut market_oracle_map = MarketOracleMap::new();
+        market_oracle_map.insert(0, MarketOracle { market, oracle_price });
+
+        // Long 10 entered at 105 is down 50 against 90 of collateral
+        let mut user = User::zeroed();
+        user.collateral = 90_000_000;
+        user.positions[0].base_asset_amount = 10 * PRECISION as i128;
+        user.positions[0].quote_asset_amount = 1_050 * PRECISION;
+        assert!(is_liquidatable(&user, &market_oracle_map).unwrap());
+
+        let margin_shortage = user
+            .free_collateral(&market_oracle_map, MarginRequirementType::LiquidationTarget)
+            .unwrap()
+            .unsigned_abs();
+        assert_eq!(margin_shortage, 30 * PRECISION);
+        let base_asset_amount = calculate_base_asset_amount_to_liquidate(
+            margin_shortage,
+            oracle_price,
+            market.maintenance_margin_ratio + market.liquidation_margin_buffer_ratio,
+            market.liquidation_fee_rate,
+        )
+        .unwrap();
+        assert_eq!(base_asset_amount, 5 * PRECISION);
+
+        // Close at the oracle price and charge the fee
+        let quote_asset_amount = base_asset_amount * oracle_price / PRECISION;
+        let pnl = user.positions[0].reduce(base_asset_amount, quote_asset_amount).unwrap();
+        assert_eq!(user.settle_realized_pnl(pnl).unwrap(), 0);
+        user.collateral -= calculate_liquidation_fee(quote_asset_amount, market.liquidation_fee_rate).unwrap();
+
+        assert_eq!(user.collateral, 60_000_000);
+        assert_eq!(user.positions[0].base_asset_amount, 5 * PRECISION as i128);
+        assert_eq!(
+            user.free_collateral(&market_oracle_map, MarginRequirementType::LiquidationTarget).unwrap(),
+            0
+        );
+        assert!(!is_liquidatable(&user, &market_oracle_map).unwrap());
+    }
+
+    #[test]
+    fn fees_above_the_target_ratio_liquidate_the_whole_position() {
+        assert_eq!(
+            calculate_base_asset_amount_to_liquidate(PRECISION, 100 * PRECISION, 50_000, 50_000).unwrap(),
+            u128::MAX
+        );
+    }
+}
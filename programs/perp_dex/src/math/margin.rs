@@ -1,74 +1,262 @@
 use anchor_lang::prelude::*;
+use std::collections::BTreeMap;
 use crate::state::user::{User, Position};
 use crate::state::market::Market;
-use crate::state::constants::{PRECISION, COLLATERAL_PRECISION};
+use crate::state::constants::{PRECISION, PRICE_TO_COLLATERAL_PRECISION_RATIO, RATIO_PRECISION};
 use crate::validation::validate_oracle_price;
 use crate::error::PerpError;
 
-pub fn meets_initial_margin_requirement(
-    user: &User,
+/// A market loaded from the remaining accounts together with its validated oracle price.
+#[derive(Clone, Copy)]
+pub struct MarketOracle {
+    pub market: Market,
+    pub oracle_price: u128,
+}
+
+/// Markets and their oracle prices, keyed by market index.
+pub type MarketOracleMap = BTreeMap<u16, MarketOracle>;
+
+/// Selects which margin ratio of each market is applied.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum MarginRequirementType {
+    Initial,
+    Maintenance,
+    /// Maintenance plus the market's liquidation buffer, the level partial liquidations restore.
+    LiquidationTarget,
+}
+
+/// Margin totals for a user across every market they hold a position in.
+pub struct MarginCalculation {
+    /// Collateral value (in price precision).
+    pub total_collateral: u128,
+
+    /// Collateral plus haircut unrealized PnL and unsettled funding (in price precision).
+    pub equity: i128,
+
+    /// Sum of position notionals at oracle price (in price precision).
+    pub total_notional: u128,
+
+    /// Sum of each position's notional weighted by its market's margin ratio.
+    /// The initial requirement also covers resting orders.
+    pub margin_requirement: u128,
+}
+
+/// Loads the markets and oracles passed as `[market, oracle]` pairs in `remaining_accounts`.
+/// Every oracle must be the feed configured on its market and each market may only appear once.
+pub fn load_market_oracle_map(
     remaining_accounts: &[AccountInfo],
-) -> Result<bool> {
-    let market_info = remaining_accounts.get(0).ok_or(PerpError::InvalidMarketIndex)?;
-    let oracle_info = remaining_accounts.get(1).ok_or(PerpError::InvalidOraclePrice)?;
+    clock: &Clock,
+) -> Result<MarketOracleMap> {
+    require!(
+        remaining_accounts.len() % 2 == 0,
+        PerpError::InvalidRemainingAccounts
+    );
 
-    let market: AccountLoader<Market> = AccountLoader::try_from(market_info)?;
-    let market = market.load()?;
-    let oracle_price = validate_oracle_price(oracle_info, &Clock::get()?)?;
+    let mut market_oracle_map = MarketOracleMap::new();
+    for pair in remaining_accounts.chunks(2) {
+        let (market_info, oracle_info) = (&pair[0], &pair[1]);
 
-    let total_position_value = get_total_position_value(user, &market, oracle_price)?;
-    let total_collateral_value =
-        user.collateral as u128 * (PRECISION / COLLATERAL_PRECISION as u128);
+        let market_loader: AccountLoader<Market> = AccountLoader::try_from(market_info)?;
+        let market = *market_loader.load()?;
+        require_keys_eq!(
+            market.oracle_price_feed,
+            oracle_info.key(),
+            PerpError::InvalidOraclePrice
+        );
+        let oracle_price = validate_oracle_price(oracle_info, clock)?;
 
-    if total_position_value == 0 {
-        return Ok(true);
+        let previous = market_oracle_map.insert(
+            market.market_index,
+            MarketOracle { market, oracle_price },
+        );
+        require!(previous.is_none(), PerpError::InvalidRemainingAccounts);
     }
 
-    let margin_ratio = total_collateral_value
-        .checked_mul(PRECISION)
-        .and_then(|n| n.checked_div(total_position_value))
-        .ok_or(PerpError::MathOverflow)?;
+    Ok(market_oracle_map)
+}
+
+/// Computes collateral, equity, notional and margin requirement over all of a user's positions.
+/// Fails if a market the user holds a position in is missing from `market_oracle_map`.
+pub fn calculate_margin(
+    user: &User,
+    market_oracle_map: &MarketOracleMap,
+    requirement_type: MarginRequirementType,
+) -> Result<MarginCalculation> {
+    let mut total_notional = 0u128;
+    let mut margin_requirement = 0u128;
+
+    for position in user.positions.iter().filter(|p| !p.is_available()) {
+        let MarketOracle { market, oracle_price } = market_oracle_map
+            .get(&position.market_index)
+            .ok_or(PerpError::MarketAccountMissing)?;
 
-    Ok(margin_ratio >= market.initial_margin_ratio as u128)
+        let notional = get_position_notional(position, *oracle_price)?;
+        // Resting orders lock initial margin as if the worse side were filled
+        let requirement_notional = if requirement_type == MarginRequirementType::Initial {
+            position
+                .get_worst_case_base_asset_amount()?
+                .checked_mul(*oracle_price)
+                .and_then(|n| n.checked_div(PRECISION))
+                .ok_or(PerpError::MathOverflow)?
+        } else {
+            notional
+        };
+        let margin_ratio = match requirement_type {
+            MarginRequirementType::Initial => market.initial_margin_ratio,
+            MarginRequirementType::Maintenance => market.maintenance_margin_ratio,
+            MarginRequirementType::LiquidationTarget => market
+                .maintenance_margin_ratio
+                .checked_add(market.liquidation_margin_buffer_ratio)
+                .ok_or(PerpError::MathOverflow)?,
+        };
+        let requirement = requirement_notional
+            .checked_mul(margin_ratio as u128)
+            .and_then(|n| n.checked_div(RATIO_PRECISION as u128))
+            .ok_or(PerpError::MathOverflow)?;
+
+        total_notional = total_notional
+            .checked_add(notional)
+            .ok_or(PerpError::MathOverflow)?;
+        margin_requirement = margin_requirement
+            .checked_add(requirement)
+            .ok_or(PerpError::MathOverflow)?;
+    }
+
+    Ok(MarginCalculation {
+        total_collateral: collateral_to_quote(user.collateral)?,
+        equity: user.equity(market_oracle_map)?,
+        total_notional,
+        margin_requirement,
+    })
 }
 
-pub fn is_liquidatable(
+pub fn meets_initial_margin_requirement(
     user: &User,
-    market: &Market,
     remaining_accounts: &[AccountInfo],
 ) -> Result<bool> {
-    let oracle_info = remaining_accounts.get(0).ok_or(PerpError::InvalidOraclePrice)?;
-    let oracle_price = validate_oracle_price(oracle_info, &Clock::get()?)?;
+    let market_oracle_map = load_market_oracle_map(remaining_accounts, &Clock::get()?)?;
+    let free_collateral = user.free_collateral(&market_oracle_map, MarginRequirementType::Initial)?;
 
-    let total_position_value = get_total_position_value(user, market, oracle_price)?;
-    let total_collateral_value =
-        user.collateral as u128 * (PRECISION / COLLATERAL_PRECISION as u128);
+    Ok(free_collateral >= 0)
+}
+
+pub fn is_liquidatable(
+    user: &User,
+    market_oracle_map: &MarketOracleMap,
+) -> Result<bool> {
+    let margin = calculate_margin(user, market_oracle_map, MarginRequirementType::Maintenance)?;
 
-    if total_position_value == 0 {
+    if margin.total_notional == 0 {
         return Ok(false);
     }
 
-    let margin_ratio = total_collateral_value
-        .checked_mul(PRECISION)
-        .and_then(|n| n.checked_div(total_position_value))
+    Ok(margin.equity < margin.margin_requirement as i128)
+}
+
+/// Converts an amount in price precision to collateral precision, rounding down.
+pub fn quote_to_collateral(amount: u128) -> Result<u64> {
+    let collateral = amount
+        .checked_div(PRICE_TO_COLLATERAL_PRECISION_RATIO)
         .ok_or(PerpError::MathOverflow)?;
+    u64::try_from(collateral).map_err(|_| PerpError::MathOverflow.into())
+}
 
-    Ok(margin_ratio < market.maintenance_margin_ratio as u128)
+/// Converts an amount in price precision to collateral precision, rounding up.
+pub fn quote_to_collateral_round_up(amount: u128) -> Result<u64> {
+    let collateral = amount
+        .checked_add(PRICE_TO_COLLATERAL_PRECISION_RATIO - 1)
+        .and_then(|a| a.checked_div(PRICE_TO_COLLATERAL_PRECISION_RATIO))
+        .ok_or(PerpError::MathOverflow)?;
+    u64::try_from(collateral).map_err(|_| PerpError::MathOverflow.into())
 }
 
-fn get_total_position_value(user: &User, market: &Market, price: u128) -> Result<u128> {
-    let mut total_value = 0u128;
-    for position in user.positions.iter() {
-        if position.market_index == market.market_index && position.base_asset_amount != 0 {
-            let value = (position.base_asset_amount.abs() as u128)
-                .checked_mul(price)
-                .and_then(|n| n.checked_div(PRECISION))
-                .ok_or(PerpError::MathOverflow)?;
-            total_value = total_value
-                .checked_add(value)
-                .ok_or(PerpError::MathOverflow)?;
+/// Converts an amount in collateral precision to price precision.
+pub fn collateral_to_quote(amount: u64) -> Result<u128> {
+    (amount as u128)
+        .checked_mul(PRICE_TO_COLLATERAL_PRECISION_RATIO)
+        .ok_or(PerpError::MathOverflow.into())
+}
+
+fn get_position_notional(position: &Position, price: u128) -> Result<u128> {
+    (position.base_asset_amount.unsigned_abs())
+        .checked_mul(price)
+        .and_then(|n| n.checked_div(PRECISION))
+        .ok_or(PerpError::MathOverflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn market(market_index: u16, initial_margin_ratio: u64, maintenance_margin_ratio: u64) -> Market {
+        Market {
+            market_index,
+            initialized: true,
+            initial_margin_ratio,
+            maintenance_margin_ratio,
+            ..Default::default()
         }
     }
-    Ok(total_value)
-}
 
+    /// A user long 1 base in market 0 and short 2 base in market 1, both entered at the oracle price.
+    fn cross_margin_user(collateral: u64) -> (User, MarketOracleMap) {
+        let mut market_oracle_map = MarketOracleMap::new();
+        market_oracle_map.insert(0, MarketOracle { market: market(0, 100_000, 50_000), oracle_price: 100 * PRECISION });
+        market_oracle_map.insert(1, MarketOracle { market: market(1, 200_000, 100_000), oracle_price: 50 * PRECISION });
+
+        let mut user = User::zeroed();
+        user.collateral = collateral;
+        user.positions[0].market_index = 0;
+        user.positions[0].base_asset_amount = PRECISION as i128;
+        user.positions[0].quote_asset_amount = 100 * PRECISION;
+        user.positions[1].market_index = 1;
+        user.positions[1].base_asset_amount = -2 * PRECISION as i128;
+        user.positions[1].quote_asset_amount = 100 * PRECISION;
+
+        (user, market_oracle_map)
+    }
+
+    #[test]
+    fn margin_sums_every_market() {
+        let (user, market_oracle_map) = cross_margin_user(40_000_000);
+
+        let margin = calculate_margin(&user, &market_oracle_map, MarginRequirementType::Initial).unwrap();
+        assert_eq!(margin.total_collateral, 40 * PRECISION);
+        assert_eq!(margin.equity, 40 * PRECISION as i128);
+        assert_eq!(margin.total_notional, 200 * PRECISION);
+        // 10% of 100 plus 20% of 100
+        assert_eq!(margin.margin_requirement, 30 * PRECISION);
+        assert_eq!(
+            user.free_collateral(&market_oracle_map, MarginRequirementType::Initial).unwrap(),
+            10 * PRECISION as i128
+        );
+    }
+
+    #[test]
+    fn gains_in_one_market_back_losses_in_another() {
+        let (user, mut market_oracle_map) = cross_margin_user(16_000_000);
+        assert!(!is_liquidatable(&user, &market_oracle_map).unwrap());
+
+        // The long loses 10 while the short gains 10
+        market_oracle_map.get_mut(&0).unwrap().oracle_price = 90 * PRECISION;
+        market_oracle_map.get_mut(&1).unwrap().oracle_price = 45 * PRECISION;
+        let margin = calculate_margin(&user, &market_oracle_map, MarginRequirementType::Maintenance).unwrap();
+        assert_eq!(margin.equity, 16 * PRECISION as i128);
+        // 5% of 90 plus 10% of 90
+        assert_eq!(margin.margin_requirement, 13_500_000_000);
+        assert!(!is_liquidatable(&user, &market_oracle_map).unwrap());
+
+        // Only the long moves against the user
+        market_oracle_map.get_mut(&1).unwrap().oracle_price = 50 * PRECISION;
+        assert!(is_liquidatable(&user, &market_oracle_map).unwrap());
+    }
+
+    #[test]
+    fn margin_requires_every_position_market() {
+        let (user, mut market_oracle_map) = cross_margin_user(40_000_000);
+        market_oracle_map.remove(&1);
+
+        assert!(calculate_margin(&user, &market_oracle_map, MarginRequirementType::Initial).is_err());
+    }
+}
@@ -1,2 +1,7 @@
 pub mod amm;
+pub mod bankruptcy;
+pub mod fees;
+pub mod funding;
+pub mod insurance;
+pub mod liquidation;
 pub mod margin;
@@ -8,23 +8,93 @@ pub const PROGRAM_SEED: &[u8] = b"perp_dex_state";
 /// Seed for the collateral vault PDA.
 pub const VAULT_SEED: &[u8] = b"collateral_vault";
 
+/// Seed for the insurance fund vault PDA.
+pub const INSURANCE_VAULT_SEED: &[u8] = b"insurance_vault";
+
+/// Seed for a staker's insurance fund stake PDA.
+pub const INSURANCE_FUND_STAKE_SEED: &[u8] = b"insurance_fund_stake";
+
 /// Seed for the market PDA.
 pub const MARKET_SEED: &[u8] = b"market";
 
 /// Seed for the user account PDA.
 pub const USER_SEED: &[u8] = b"user";
 
+/// Seed for a market's order book PDA.
+pub const ORDER_BOOK_SEED: &[u8] = b"order_book";
+
+/// Seed for a taker's JIT auction PDA.
+pub const TAKER_AUCTION_SEED: &[u8] = b"taker_auction";
+
 /// Precision for prices and assets (10^9).
 pub const PRECISION: u128 = 1_000_000_000;
 
+/// Precision for the vAMM peg multiplier (10^6).
+pub const PEG_PRECISION: u128 = 1_000_000;
+
 /// Precision for collateral (USDC, 10^6).
 pub const COLLATERAL_PRECISION: u64 = 1_000_000;
 
+/// Factor converting amounts in collateral precision to price precision.
+pub const PRICE_TO_COLLATERAL_PRECISION_RATIO: u128 = PRECISION / COLLATERAL_PRECISION as u128;
+
 /// Maximum number of positions a user can hold.
 pub const MAX_POSITIONS: usize = 8;
 
+/// Maximum number of trigger orders a user can hold.
+pub const MAX_TRIGGER_ORDERS: usize = 4;
+
+/// Maximum number of TWAP orders a user can hold.
+pub const MAX_TWAP_ORDERS: usize = 2;
+
+/// Maximum number of resting limit orders a user can hold.
+pub const MAX_LIMIT_ORDERS: usize = 8;
+
+/// Share of a limit order fill's notional paid by the order's owner to the keeper that fills it
+/// (0.01%, scaled by 1_000_000).
+pub const LIMIT_ORDER_FILLER_REWARD_RATE: u64 = 100;
+
+/// Maximum number of resting orders on each side of an order book.
+pub const MAX_ORDERS_PER_SIDE: usize = 32;
+
+/// Slots a taker auction stays open to JIT makers before settling against the vAMM.
+pub const JIT_AUCTION_DURATION_SLOTS: u64 = 10;
+
+/// Multiple of the base spread added to the side growing a fully skewed user inventory.
+pub const MAX_INVENTORY_SPREAD_SCALE: u128 = 10;
+
 /// Oracle price validity duration in seconds (e.g., 60 seconds).
 pub const ORACLE_STALENESS_THRESHOLD: i64 = 60;
 
 /// Funding rate period in seconds (e.g., 1 hour).
 pub const FUNDING_PERIOD: i64 = 3600;
+
+/// Seconds in a day, the horizon the mark-oracle premium is funded over.
+pub const ONE_DAY: i64 = 86_400;
+
+/// Default maximum funding rate per period as a share of the oracle TWAP (0.1%).
+pub const DEFAULT_MAX_FUNDING_RATE: u64 = 1_000;
+
+/// Default margin buffer above maintenance restored by partial liquidations (scaled by 1_000_000).
+pub const DEFAULT_LIQUIDATION_MARGIN_BUFFER_RATIO: u64 = 20_000;
+
+/// Default share of the liquidation fee paid to the liquidator (scaled by 1_000_000).
+pub const DEFAULT_LIQUIDATOR_FEE_SHARE: u64 = 500_000;
+
+/// Default discount to oracle at which liquidators take over positions (scaled by 1_000_000).
+pub const DEFAULT_LIQUIDATION_TRANSFER_DISCOUNT: u64 = 10_000;
+
+/// Default share of trade fees allotted to the insurance fund (scaled by 1_000_000).
+pub const DEFAULT_INSURANCE_FEE_SHARE: u64 = 500_000;
+
+/// Default maximum divergence of the mark price from the oracle price after a vAMM fill (10%).
+pub const DEFAULT_MAX_MARK_ORACLE_DIVERGENCE_BPS: u64 = 1_000;
+
+/// Default smallest order size accepted on an order book (0.001 base).
+pub const DEFAULT_MIN_ORDER_SIZE: u128 = 1_000_000;
+
+/// Basis points in one (10^4).
+pub const BPS_PRECISION: u128 = 10_000;
+
+/// Precision for fee and margin ratios (10^6).
+pub const RATIO_PRECISION: u64 = 1_000_000;
@@ -1,7 +1,26 @@
 use anchor_lang::prelude::*;
 use bytemuck::{Pod, Zeroable};
 
-use crate::state::constants::PRECISION;
+use crate::state::constants::{MAX_ORDERS_PER_SIDE, PEG_PRECISION, PRECISION, RATIO_PRECISION};
+use crate::math::amm::{
+    calculate_k_update_cost, calculate_max_base_asset_amount_within_limit,
+    calculate_quote_asset_amount, calculate_repeg_cost, calculate_spreads, calculate_swap_output,
+    TradeDirection,
+};
+use crate::math::bankruptcy::{calculate_adl_score, calculate_loss_per_base};
+use crate::math::funding::{calculate_funding_payments, calculate_new_twap, FundingPayments};
+use crate::math::margin::{collateral_to_quote, quote_to_collateral, quote_to_collateral_round_up};
+use crate::error::PerpError;
+
+/// How a market absorbs a bankrupt account's deficit once the insurance fund is exhausted.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum BankruptcyMode {
+    /// Spread the deficit across the opposite side's positions via the cumulative loss index.
+    SocializeLoss,
+
+    /// Recover the deficit by force-reducing the most profitable opposite-side positions.
+    AutoDeleverage,
+}
 
 /// Represents a single perpetuals market.
 #[account(zero_copy)]
@@ -51,12 +70,153 @@ pub struct Market {
     /// Total open interest in base asset terms.
     pub open_interest_base: u128,
 
+    // Fields appended to the original layout, grouped by alignment. Accounts created before
+    // them are grown by `migrate_market`.
+
+    // vAMM adjustment
+    /// Multiplier converting the vAMM's reserve price into the mark price (scaled by 1_000_000).
+    pub peg_multiplier: u128,
+
+    /// Lowest `amm_k_constant` the adjustment may shrink the vAMM to.
+    pub min_k_constant: u128,
+
+    /// Highest `amm_k_constant` the adjustment may grow the vAMM to.
+    pub max_k_constant: u128,
+
+    // Oracle
+    /// Oracle confidence interval at the last oracle read (in price precision).
+    pub last_oracle_confidence: u128,
+
+    // Order book
+    /// Smallest order size accepted on the market's order book (in base precision).
+    pub min_order_size: u128,
+
+    // Liquidation
+    /// Maximum base asset amount liquidated per slot, zero for no limit.
+    pub max_liquidation_base_per_slot: u128,
+
+    /// Base asset amount liquidated in `last_liquidation_slot`.
+    pub liquidated_base_in_slot: u128,
+
+    // Funding rate
+    /// Mark price averaged over the last funding period (in price precision).
+    pub last_mark_price_twap: u128,
+
+    /// Mark price the vAMM was left at by its last trade or adjustment (in price precision).
+    pub last_mark_price: u128,
+
+    /// Oracle price averaged over the last funding period (in price precision).
+    pub last_oracle_price_twap: u128,
+
+    /// Cumulative funding paid per unit of long base (in price precision).
+    pub cumulative_funding_rate_long: i128,
+
+    /// Cumulative funding paid per unit of short base (in price precision).
+    pub cumulative_funding_rate_short: i128,
+
+    /// Total funding charged to the paying side (in price precision).
+    pub total_funding_paid: u128,
+
+    /// Total funding credited to the receiving side (in price precision).
+    /// Any difference to `total_funding_paid` was settled against the fee pool.
+    pub total_funding_received: u128,
+
+    // Open Interest
+    /// Sum of all long positions' base asset amounts.
+    pub base_asset_amount_long: i128,
+
+    /// Sum of all short positions' base asset amounts (non-positive).
+    pub base_asset_amount_short: i128,
+
+    /// Sum of all long positions' quote asset amounts, their cost basis (in price precision).
+    pub quote_asset_amount_long: u128,
+
+    /// Sum of all short positions' quote asset amounts, their cost basis (in price precision).
+    pub quote_asset_amount_short: u128,
+
+    // Bankruptcy
+    /// Cumulative socialized loss per unit of long base (in price precision).
+    pub cumulative_loss_per_base_long: u128,
+
+    /// Cumulative socialized loss per unit of short base (in price precision).
+    pub cumulative_loss_per_base_short: u128,
+
+    // vAMM adjustment
+    /// Fee pool level (in collateral precision) above which funding updates deepen and repeg the
+    /// vAMM out of the surplus, and below which they make it shallower.
+    pub amm_fee_pool_target: u64,
+
+    /// Most the reserves and peg move in one funding update's adjustment (scaled by 1_000_000),
+    /// zero to disable the adjustment.
+    pub max_amm_adjustment_ratio: u64,
+
+    /// Spread charged on each side before inventory, confidence and divergence (scaled by 1_000_000).
+    pub base_spread: u64,
+
+    /// Cap on each side's spread (scaled by 1_000_000).
+    pub max_spread: u64,
+
+    /// Maximum divergence of the mark price from the oracle price a vAMM fill may leave
+    /// (in basis points), zero for no limit.
+    pub max_mark_oracle_divergence_bps: u64,
+
+    // Liquidation
+    /// Share of the liquidation fee paid to the liquidator, the rest goes to insurance (scaled by 1_000_000).
+    pub liquidator_fee_share: u64,
+
+    /// Discount to oracle at which a liquidator takes over a position (scaled by 1_000_000).
+    pub liquidation_transfer_discount: u64,
+
+    /// Margin buffer above maintenance that partial liquidations restore (scaled by 1_000_000).
+    pub liquidation_margin_buffer_ratio: u64,
+
+    /// Slot of the most recent liquidation.
+    pub last_liquidation_slot: u64,
+
+    // Fees
+    /// Share of trade fees allotted to the insurance fund (scaled by 1_000_000).
+    pub insurance_fee_share: u64,
+
+    // Fee pool (in collateral precision)
+    /// Total trade fees ever collected.
+    pub total_fee: u64,
+
+    /// Fees withdrawable by the protocol, also backing the AMM's own PnL.
+    pub fee_pool: u64,
+
+    /// Fees allotted to the insurance fund.
+    pub insurance_fee_pool: u64,
+
+    // Margin requirements
+    /// Share of positive unrealized PnL excluded from equity (scaled by 1_000_000).
+    pub unrealized_pnl_haircut: u64,
+
+    // Funding rate
+    /// Maximum funding rate per period as a share of the oracle TWAP (scaled by 1_000_000).
+    pub max_funding_rate: u64,
+
+    /// Timestamp the mark price TWAP was last updated.
+    pub last_mark_price_twap_ts: i64,
+
+    /// Timestamp the oracle price TWAP was last updated.
+    pub last_oracle_price_twap_ts: i64,
+
+    // Bankruptcy
+    /// Deficit awaiting recovery from long positions through ADL (in collateral precision).
+    pub pending_adl_deficit_long: u64,
+
+    /// Deficit awaiting recovery from short positions through ADL (in collateral precision).
+    pub pending_adl_deficit_short: u64,
+
+    /// `BankruptcyMode` applied once the insurance fund is exhausted.
+    pub bankruptcy_mode: u8,
+
     /// Padding for future upgrades.
-    pub _padding: [u8; 256],
+    pub _padding: [u8; 23],
 }
 
 impl Market {
-    /// Computes the mark price of the market using the vAMM reserves.
+    /// Computes the mark price of the market using the vAMM reserves and peg.
     pub fn get_mark_price(&self) -> Result<u128> {
         if self.amm_base_asset_reserve == 0 {
             return Ok(0);
@@ -64,8 +224,980 @@ impl Market {
 
         self.amm_quote_asset_reserve
             .checked_mul(PRECISION)
+            .and_then(|n| n.checked_mul(self.peg_multiplier))
             .and_then(|n| n.checked_div(self.amm_base_asset_reserve))
+            .and_then(|n| n.checked_div(PEG_PRECISION))
             .ok_or(ErrorCode::InvalidCalculation.into())
     }
+
+    /// Returns the current (long, short) spreads (scaled by 1_000_000).
+    pub fn get_spreads(&self) -> Result<(u64, u64)> {
+        calculate_spreads(
+            self.base_spread,
+            self.max_spread,
+            self.get_net_user_base_asset_amount()?,
+            self.amm_base_asset_reserve,
+            self.last_oracle_confidence,
+            self.last_oracle_price_twap,
+            self.last_mark_price_twap,
+        )
+    }
+
+    /// Widens a curve quote by the spread: longs pay the ask, shorts receive the bid.
+    /// Returns the quote the trader pays or receives and the spread collected (in price precision).
+    pub fn apply_spread(
+        &self,
+        quote_asset_amount: u128,
+        direction: TradeDirection,
+    ) -> Result<(u128, u128)> {
+        let (long_spread, short_spread) = self.get_spreads()?;
+        let spread = match direction {
+            TradeDirection::Long => long_spread,
+            TradeDirection::Short => short_spread,
+        };
+        let spread_amount = quote_asset_amount
+            .checked_mul(spread as u128)
+            .and_then(|s| s.checked_div(RATIO_PRECISION as u128))
+            .ok_or(PerpError::MathOverflow)?;
+
+        let quote_asset_amount = match direction {
+            TradeDirection::Long => quote_asset_amount.checked_add(spread_amount),
+            TradeDirection::Short => quote_asset_amount.checked_sub(spread_amount),
+        }
+        .ok_or(PerpError::MathOverflow)?;
+
+        Ok((quote_asset_amount, spread_amount))
+    }
+
+    /// Swaps `base_asset_amount` along the vAMM curve in `direction`, paying the spread into the
+    /// fee pool. Returns the quote asset amount the trader pays or receives (in price precision).
+    pub fn swap_base_asset(
+        &mut self,
+        base_asset_amount: u128,
+        direction: TradeDirection,
+    ) -> Result<u128> {
+        let (new_quote_asset_reserve, new_base_asset_reserve) = calculate_swap_output(
+            base_asset_amount,
+            self.amm_base_asset_reserve,
+            self.amm_quote_asset_reserve,
+            direction,
+        )?;
+        let curve_quote_asset_amount = calculate_quote_asset_amount(
+            self.amm_quote_asset_reserve.abs_diff(new_quote_asset_reserve),
+            self.peg_multiplier,
+        )?;
+        let (quote_asset_amount, spread_amount) =
+            self.apply_spread(curve_quote_asset_amount, direction)?;
+
+        self.amm_base_asset_reserve = new_base_asset_reserve;
+        self.amm_quote_asset_reserve = new_quote_asset_reserve;
+        self.record_mark_price()?;
+        self.fee_pool = self
+            .fee_pool
+            .checked_add(quote_to_collateral(spread_amount)?)
+            .ok_or(PerpError::MathOverflow)?;
+
+        Ok(quote_asset_amount)
+    }
+
+    /// Returns the largest base asset amount that trades against the vAMM in `direction` with an
+    /// average price, spread included, no worse than `limit_price`.
+    pub fn get_max_base_asset_amount_within_limit(
+        &self,
+        limit_price: u128,
+        direction: TradeDirection,
+    ) -> Result<u128> {
+        let (long_spread, short_spread) = self.get_spreads()?;
+        let curve_limit_price = match direction {
+            TradeDirection::Long => limit_price
+                .checked_mul(RATIO_PRECISION as u128)
+                .and_then(|p| p.checked_div((RATIO_PRECISION + long_spread) as u128)),
+            // Rounded up so the bid after the spread still clears the limit
+            TradeDirection::Short => {
+                let denominator = RATIO_PRECISION.saturating_sub(short_spread) as u128;
+                limit_price
+                    .checked_mul(RATIO_PRECISION as u128)
+                    .and_then(|p| p.checked_add(denominator - 1))
+                    .and_then(|p| p.checked_div(denominator))
+            }
+        }
+        .ok_or(PerpError::MathOverflow)?;
+
+        calculate_max_base_asset_amount_within_limit(
+            self.amm_base_asset_reserve,
+            self.amm_quote_asset_reserve,
+            self.peg_multiplier,
+            curve_limit_price,
+            direction,
+        )
+    }
+
+    /// Returns the users' net base position against the vAMM, positive if users are net long.
+    pub fn get_net_user_base_asset_amount(&self) -> Result<i128> {
+        self.base_asset_amount_long
+            .checked_add(self.base_asset_amount_short)
+            .ok_or(PerpError::MathOverflow.into())
+    }
+
+    /// Scales both vAMM reserves by `scale_factor` (scaled by 1_000_000), keeping the mark price
+    /// and changing the liquidity depth. The resulting cost to the vAMM is settled against the
+    /// fee pool.
+    pub fn scale_reserves(&mut self, scale_factor: u64) -> Result<()> {
+        let (new_base_asset_reserve, new_quote_asset_reserve) =
+            self.get_scaled_reserves(scale_factor)?;
+        let cost = self.calculate_scale_reserves_cost(scale_factor)?;
+        self.settle_amm_cost(cost)?;
+
+        self.amm_base_asset_reserve = new_base_asset_reserve;
+        self.amm_quote_asset_reserve = new_quote_asset_reserve;
+        self.amm_k_constant = new_base_asset_reserve
+            .checked_mul(new_quote_asset_reserve)
+            .ok_or(PerpError::MathOverflow)?;
+
+        self.record_mark_price()
+    }
+
+    /// Returns the base and quote reserves scaled by `scale_factor` (scaled by 1_000_000).
+    fn get_scaled_reserves(&self, scale_factor: u64) -> Result<(u128, u128)> {
+        let scale = |reserve: u128| {
+            reserve
+                .checked_mul(scale_factor as u128)
+                .and_then(|r| r.checked_div(RATIO_PRECISION as u128))
+                .ok_or(PerpError::MathOverflow)
+        };
+        let new_base_asset_reserve = scale(self.amm_base_asset_reserve)?;
+        let new_quote_asset_reserve = scale(self.amm_quote_asset_reserve)?;
+        require!(
+            new_base_asset_reserve > 0 && new_quote_asset_reserve > 0,
+            PerpError::UnhealthyMarketState
+        );
+
+        Ok((new_base_asset_reserve, new_quote_asset_reserve))
+    }
+
+    /// Returns the cost to the vAMM (in price precision) of scaling its reserves by `scale_factor`.
+    pub fn calculate_scale_reserves_cost(&self, scale_factor: u64) -> Result<i128> {
+        let (new_base_asset_reserve, new_quote_asset_reserve) =
+            self.get_scaled_reserves(scale_factor)?;
+        calculate_k_update_cost(
+            self.amm_base_asset_reserve,
+            self.amm_quote_asset_reserve,
+            new_base_asset_reserve,
+            new_quote_asset_reserve,
+            self.get_net_user_base_asset_amount()?,
+            self.peg_multiplier,
+        )
+    }
+
+    /// Self-tunes the vAMM on a funding update. With the fee pool above its target, the peg is
+    /// nudged toward `oracle_price_twap` and the reserves are deepened, each only if the surplus
+    /// covers its cost. With the fee pool below target, the reserves are made shallower.
+    /// Moves are limited to `max_amm_adjustment_ratio` and k stays within its bounds; an
+    /// adjustment that would break a bound or cannot be paid for is skipped.
+    pub fn apply_formulaic_amm_adjustment(&mut self, oracle_price_twap: u128) -> Result<()> {
+        if self.max_amm_adjustment_ratio == 0 {
+            return Ok(());
+        }
+
+        if self.fee_pool > self.amm_fee_pool_target {
+            let mut surplus = self.fee_pool - self.amm_fee_pool_target;
+
+            let mark_price = self.get_mark_price()?;
+            if mark_price > 0 && mark_price != oracle_price_twap {
+                let target_peg_multiplier = self
+                    .peg_multiplier
+                    .checked_mul(oracle_price_twap)
+                    .and_then(|p| p.checked_div(mark_price))
+                    .ok_or(PerpError::MathOverflow)?;
+                let max_peg_delta = self
+                    .peg_multiplier
+                    .checked_mul(self.max_amm_adjustment_ratio as u128)
+                    .and_then(|d| d.checked_div(RATIO_PRECISION as u128))
+                    .ok_or(PerpError::MathOverflow)?;
+                let new_peg_multiplier = target_peg_multiplier
+                    .clamp(
+                        self.peg_multiplier.saturating_sub(max_peg_delta),
+                        self.peg_multiplier.saturating_add(max_peg_delta),
+                    )
+                    .max(1);
+
+                let cost = calculate_repeg_cost(
+                    self.amm_base_asset_reserve,
+                    self.amm_quote_asset_reserve,
+                    self.get_net_user_base_asset_amount()?,
+                    self.peg_multiplier,
+                    new_peg_multiplier,
+                )?;
+                if let Some(remaining_surplus) = get_remaining_budget(surplus, cost)? {
+                    self.settle_amm_cost(cost)?;
+                    self.peg_multiplier = new_peg_multiplier;
+                    self.record_mark_price()?;
+                    surplus = remaining_surplus;
+                }
+            }
+
+            let scale_factor = RATIO_PRECISION + self.max_amm_adjustment_ratio;
+            let (new_base_asset_reserve, new_quote_asset_reserve) =
+                self.get_scaled_reserves(scale_factor)?;
+            let new_k = new_base_asset_reserve.checked_mul(new_quote_asset_reserve);
+            let cost = self.calculate_scale_reserves_cost(scale_factor)?;
+            if new_k.is_some_and(|k| k <= self.max_k_constant)
+                && get_remaining_budget(surplus, cost)?.is_some()
+            {
+                self.scale_reserves(scale_factor)?;
+            }
+        } else if self.fee_pool < self.amm_fee_pool_target {
+            let scale_factor = RATIO_PRECISION - self.max_amm_adjustment_ratio;
+            let (new_base_asset_reserve, new_quote_asset_reserve) =
+                self.get_scaled_reserves(scale_factor)?;
+            let new_k = new_base_asset_reserve
+                .checked_mul(new_quote_asset_reserve)
+                .ok_or(PerpError::MathOverflow)?;
+            let cost = self.calculate_scale_reserves_cost(scale_factor)?;
+            if new_k >= self.min_k_constant && get_remaining_budget(self.fee_pool, cost)?.is_some() {
+                self.scale_reserves(scale_factor)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the fee pool (in collateral precision) needed to back the vAMM's own PnL: the cost,
+    /// given the users' net position against it, of moving the mark price to the oracle TWAP.
+    pub fn get_amm_pnl_exposure(&self) -> Result<u64> {
+        let mark_price = self.get_mark_price()?;
+        if mark_price == 0 || self.last_oracle_price_twap == 0 {
+            return Ok(0);
+        }
+
+        let target_peg_multiplier = self
+            .peg_multiplier
+            .checked_mul(self.last_oracle_price_twap)
+            .and_then(|p| p.checked_div(mark_price))
+            .ok_or(PerpError::MathOverflow)?
+            .max(1);
+        let cost = calculate_repeg_cost(
+            self.amm_base_asset_reserve,
+            self.amm_quote_asset_reserve,
+            self.get_net_user_base_asset_amount()?,
+            self.peg_multiplier,
+            target_peg_multiplier,
+        )?;
+        if cost <= 0 {
+            return Ok(0);
+        }
+
+        quote_to_collateral_round_up(cost.unsigned_abs())
+    }
+
+    /// Charges a cost of adjusting the vAMM (in price precision) to the fee pool, rounded against
+    /// the pool, or credits it with a negative cost. Fails if the fee pool cannot cover the cost.
+    pub fn settle_amm_cost(&mut self, cost: i128) -> Result<()> {
+        if cost > 0 {
+            let cost = quote_to_collateral_round_up(cost.unsigned_abs())?;
+            self.fee_pool = self
+                .fee_pool
+                .checked_sub(cost)
+                .ok_or(PerpError::InsufficientFeePool)?;
+        } else {
+            let profit = quote_to_collateral(cost.unsigned_abs())?;
+            self.fee_pool = self
+                .fee_pool
+                .checked_add(profit)
+                .ok_or(PerpError::MathOverflow)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configured bankruptcy mode.
+    pub fn get_bankruptcy_mode(&self) -> Result<BankruptcyMode> {
+        match self.bankruptcy_mode {
+            0 => Ok(BankruptcyMode::SocializeLoss),
+            1 => Ok(BankruptcyMode::AutoDeleverage),
+            _ => Err(PerpError::UnhealthyMarketState.into()),
+        }
+    }
+
+    /// Folds the mark price held since the last update into the mark price TWAP.
+    /// Must run before a trade or adjustment moves the mark price.
+    pub fn update_mark_price_twap(&mut self, now: i64) -> Result<()> {
+        self.last_mark_price_twap = calculate_new_twap(
+            self.last_mark_price_twap,
+            self.last_mark_price_twap_ts,
+            self.last_mark_price,
+            now,
+            self.funding_period,
+        )?;
+        self.last_mark_price_twap_ts = now;
+
+        Ok(())
+    }
+
+    /// Records the current mark price as the one held until the next TWAP update.
+    /// Must run after every trade or adjustment that moves the mark price.
+    pub fn record_mark_price(&mut self) -> Result<()> {
+        self.last_mark_price = self.get_mark_price()?;
+
+        Ok(())
+    }
+
+    /// Folds an oracle price read into the oracle price TWAP.
+    pub fn update_oracle_price_twap(&mut self, oracle_price: u128, now: i64) -> Result<()> {
+        self.last_oracle_price_twap = calculate_new_twap(
+            self.last_oracle_price_twap,
+            self.last_oracle_price_twap_ts,
+            oracle_price,
+            now,
+            self.funding_period,
+        )?;
+        self.last_oracle_price_twap_ts = now;
+
+        Ok(())
+    }
+
+    /// Returns the cumulative funding rate for the given side.
+    pub fn get_cumulative_funding_rate(&self, is_long: bool) -> i128 {
+        if is_long {
+            self.cumulative_funding_rate_long
+        } else {
+            self.cumulative_funding_rate_short
+        }
+    }
+
+    /// Records a new funding rate and accrues it into both sides' cumulative funding rates.
+    /// The paying side is charged the full rate. The imbalance between the sides is settled
+    /// against the fee pool, and once the fee pool is exhausted the receiving side's rate is
+    /// scaled down so payouts never exceed what is paid in.
+    pub fn apply_funding_rate(&mut self, funding_rate: i128, now: i64) -> Result<()> {
+        let FundingPayments {
+            funding_rate_long,
+            funding_rate_short,
+            funding_paid,
+            funding_received,
+        } = calculate_funding_payments(
+            funding_rate,
+            self.base_asset_amount_long.unsigned_abs(),
+            self.base_asset_amount_short.unsigned_abs(),
+            collateral_to_quote(self.fee_pool)?,
+        )?;
+
+        // A surplus accrues to the fee pool, a shortfall is drawn from it (rounded against the pool)
+        if funding_paid >= funding_received {
+            let surplus = quote_to_collateral(funding_paid - funding_received)?;
+            self.fee_pool = self
+                .fee_pool
+                .checked_add(surplus)
+                .ok_or(PerpError::MathOverflow)?;
+        } else {
+            let shortfall = quote_to_collateral_round_up(funding_received - funding_paid)?;
+            self.fee_pool = self
+                .fee_pool
+                .checked_sub(shortfall)
+                .ok_or(PerpError::MathOverflow)?;
+        }
+
+        self.total_funding_paid = self
+            .total_funding_paid
+            .checked_add(funding_paid)
+            .ok_or(PerpError::MathOverflow)?;
+        self.total_funding_received = self
+            .total_funding_received
+            .checked_add(funding_received)
+            .ok_or(PerpError::MathOverflow)?;
+        self.cumulative_funding_rate_long = self
+            .cumulative_funding_rate_long
+            .checked_add(funding_rate_long)
+            .ok_or(PerpError::MathOverflow)?;
+        self.cumulative_funding_rate_short = self
+            .cumulative_funding_rate_short
+            .checked_add(funding_rate_short)
+            .ok_or(PerpError::MathOverflow)?;
+        self.last_funding_rate = funding_rate;
+        self.last_funding_ts = now;
+
+        Ok(())
+    }
+
+    /// Books funding a payer could not pay (in collateral precision) after the receiving side was
+    /// credited in full. The fee pool covers what it can and the rest is charged to the receiving
+    /// side as a deficit; the unpaid amount is taken off `total_funding_paid`.
+    pub fn absorb_unpaid_funding(&mut self, unpaid: u64, payer_is_long: bool) -> Result<()> {
+        if unpaid == 0 {
+            return Ok(());
+        }
+
+        self.total_funding_paid = self
+            .total_funding_paid
+            .saturating_sub(collateral_to_quote(unpaid)?);
+
+        let fee_pool_draw = unpaid.min(self.fee_pool);
+        self.fee_pool -= fee_pool_draw;
+        self.absorb_deficit(unpaid - fee_pool_draw, !payer_is_long)
+    }
+
+    /// Returns the cumulative socialized loss per base for the given side.
+    pub fn get_cumulative_loss_per_base(&self, is_long: bool) -> u128 {
+        if is_long {
+            self.cumulative_loss_per_base_long
+        } else {
+            self.cumulative_loss_per_base_short
+        }
+    }
+
+    /// Moves a position's contribution to its side's open interest and cost basis from its old
+    /// to its new base and quote asset amounts.
+    pub fn update_open_interest(
+        &mut self,
+        old_base_asset_amount: i128,
+        old_quote_asset_amount: u128,
+        new_base_asset_amount: i128,
+        new_quote_asset_amount: u128,
+    ) -> Result<()> {
+        for (base_asset_amount, quote_asset_amount, sign) in [
+            (old_base_asset_amount, old_quote_asset_amount, -1i128),
+            (new_base_asset_amount, new_quote_asset_amount, 1),
+        ] {
+            let (side_base_asset_amount, side_quote_asset_amount) = if base_asset_amount > 0 {
+                (&mut self.base_asset_amount_long, &mut self.quote_asset_amount_long)
+            } else if base_asset_amount < 0 {
+                (&mut self.base_asset_amount_short, &mut self.quote_asset_amount_short)
+            } else {
+                continue;
+            };
+            *side_base_asset_amount = base_asset_amount
+                .checked_mul(sign)
+                .and_then(|b| side_base_asset_amount.checked_add(b))
+                .ok_or(PerpError::MathOverflow)?;
+            *side_quote_asset_amount = (quote_asset_amount as i128)
+                .checked_mul(sign)
+                .and_then(|q| side_quote_asset_amount.checked_add_signed(q))
+                .ok_or(PerpError::MathOverflow)?;
+        }
+
+        self.open_interest_base = self
+            .base_asset_amount_long
+            .checked_sub(self.base_asset_amount_short)
+            .ok_or(PerpError::MathOverflow)? as u128;
+
+        Ok(())
+    }
+
+    /// Spreads a deficit (in price precision) across one side's open interest.
+    /// Returns false if that side has no open interest to absorb it.
+    pub fn socialize_loss(&mut self, deficit: u128, charge_longs: bool) -> Result<bool> {
+        let open_interest = if charge_longs {
+            self.base_asset_amount_long.unsigned_abs()
+        } else {
+            self.base_asset_amount_short.unsigned_abs()
+        };
+        if open_interest == 0 {
+            return Ok(false);
+        }
+
+        let loss_per_base = calculate_loss_per_base(deficit, open_interest)?;
+        let cumulative_loss_per_base = if charge_longs {
+            &mut self.cumulative_loss_per_base_long
+        } else {
+            &mut self.cumulative_loss_per_base_short
+        };
+        *cumulative_loss_per_base = cumulative_loss_per_base
+            .checked_add(loss_per_base)
+            .ok_or(PerpError::MathOverflow)?;
+
+        Ok(true)
+    }
+
+    /// Records a deficit to be recovered from one side's positions through ADL.
+    pub fn add_pending_adl_deficit(&mut self, deficit: u64, charge_longs: bool) -> Result<()> {
+        let pending_adl_deficit = if charge_longs {
+            &mut self.pending_adl_deficit_long
+        } else {
+            &mut self.pending_adl_deficit_short
+        };
+        *pending_adl_deficit = pending_adl_deficit
+            .checked_add(deficit)
+            .ok_or(PerpError::MathOverflow)?;
+
+        Ok(())
+    }
+
+    /// Returns the ADL score of an unleveraged position entered at the side's average entry price,
+    /// the least a position must score to be deleveraged. Zero if the side is not in profit.
+    pub fn get_adl_score_bound(&self, oracle_price: u128, is_long: bool) -> Result<u128> {
+        let (base_asset_amount, quote_asset_amount) = if is_long {
+            (self.base_asset_amount_long, self.quote_asset_amount_long)
+        } else {
+            (self.base_asset_amount_short, self.quote_asset_amount_short)
+        };
+        let notional = base_asset_amount
+            .unsigned_abs()
+            .checked_mul(oracle_price)
+            .and_then(|n| n.checked_div(PRECISION))
+            .ok_or(PerpError::MathOverflow)?;
+
+        let unrealized_pnl = if is_long {
+            notional as i128 - quote_asset_amount as i128
+        } else {
+            quote_asset_amount as i128 - notional as i128
+        };
+        if unrealized_pnl <= 0 || notional == 0 {
+            return Ok(0);
+        }
+
+        calculate_adl_score(unrealized_pnl.unsigned_abs(), quote_asset_amount, notional, notional)
+    }
+
+    /// Charges a deficit (in collateral precision) to one side per the market's bankruptcy mode.
+    /// A deficit that cannot be socialized because the side has no open interest is queued for ADL.
+    pub fn absorb_deficit(&mut self, deficit: u64, charge_longs: bool) -> Result<()> {
+        if deficit == 0 {
+            return Ok(());
+        }
+
+        let socialized = match self.get_bankruptcy_mode()? {
+            BankruptcyMode::SocializeLoss => {
+                self.socialize_loss(collateral_to_quote(deficit)?, charge_longs)?
+            }
+            BankruptcyMode::AutoDeleverage => false,
+        };
+        if !socialized {
+            self.add_pending_adl_deficit(deficit, charge_longs)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns how much base can still be liquidated in `slot` under the per-slot limit.
+    pub fn get_liquidation_capacity(&self, slot: u64) -> u128 {
+        if self.max_liquidation_base_per_slot == 0 {
+            return u128::MAX;
+        }
+
+        let liquidated_base_in_slot = if slot == self.last_liquidation_slot {
+            self.liquidated_base_in_slot
+        } else {
+            0
+        };
+        self.max_liquidation_base_per_slot
+            .saturating_sub(liquidated_base_in_slot)
+    }
+
+    /// Records base liquidated in `slot` against the per-slot limit.
+    pub fn record_liquidation(&mut self, slot: u64, base_asset_amount: u128) -> Result<()> {
+        if slot != self.last_liquidation_slot {
+            self.last_liquidation_slot = slot;
+            self.liquidated_base_in_slot = 0;
+        }
+
+        self.liquidated_base_in_slot = self
+            .liquidated_base_in_slot
+            .checked_add(base_asset_amount)
+            .ok_or(PerpError::MathOverflow)?;
+
+        Ok(())
+    }
+
+    /// Accrues a collected trade fee, splitting it between the fee pool and the insurance share.
+    pub fn collect_fee(&mut self, fee: u64) -> Result<()> {
+        let insurance_fee = (fee as u128)
+            .checked_mul(self.insurance_fee_share as u128)
+            .and_then(|f| f.checked_div(RATIO_PRECISION as u128))
+            .ok_or(PerpError::MathOverflow)? as u64;
+        let protocol_fee = fee
+            .checked_sub(insurance_fee)
+            .ok_or(PerpError::MathOverflow)?;
+
+        self.total_fee = self.total_fee.checked_add(fee).ok_or(PerpError::MathOverflow)?;
+        self.fee_pool = self
+            .fee_pool
+            .checked_add(protocol_fee)
+            .ok_or(PerpError::MathOverflow)?;
+        self.insurance_fee_pool = self
+            .insurance_fee_pool
+            .checked_add(insurance_fee)
+            .ok_or(PerpError::MathOverflow)?;
+
+        Ok(())
+    }
+}
+
+/// Returns what is left of `budget` (in collateral precision) after paying `cost`
+/// (in price precision, negative for a profit), or `None` if the budget cannot cover it.
+fn get_remaining_budget(budget: u64, cost: i128) -> Result<Option<u64>> {
+    if cost <= 0 {
+        let profit = quote_to_collateral(cost.unsigned_abs())?;
+        return Ok(Some(budget.saturating_add(profit)));
+    }
+
+    let cost = quote_to_collateral_round_up(cost.unsigned_abs())?;
+    Ok(budget.checked_sub(cost))
 }
 
+/// A resting limit order on a market's order book.
+#[zero_copy]
+#[repr(C)]
+#[derive(Default, Pod, Zeroable)]
+pub struct Order {
+    /// Limit price (in price precision).
+    pub price: u128,
+
+    /// Unfilled base asset amount.
+    pub base_asset_amount: u128,
+
+    /// User account that placed the order.
+    pub user: Pubkey,
+
+    /// Increasing order id, also the order's time priority within a price level.
+    pub order_id: u64,
+
+    /// Timestamp after which the order can no longer fill, zero for no expiry.
+    pub max_ts: i64,
+}
+
+/// A market's limit order book. Each side is kept sorted by price-time priority,
+/// best order first: bids by descending price, asks by ascending price.
+#[account(zero_copy)]
+#[repr(C)]
+pub struct OrderBook {
+    /// Index of the market the book belongs to.
+    pub market_index: u16,
+
+    /// PDA bump.
+    pub bump: u8,
+
+    /// Number of resting bids.
+    pub num_bids: u8,
+
+    /// Number of resting asks.
+    pub num_asks: u8,
+
+    pub _padding: [u8; 3],
+
+    /// Id assigned to the next order placed.
+    pub next_order_id: u64,
+
+    pub bids: [Order; MAX_ORDERS_PER_SIDE],
+    pub asks: [Order; MAX_ORDERS_PER_SIDE],
+}
+
+impl OrderBook {
+    /// Returns the resting orders on one side, best first.
+    pub fn orders(&self, is_bid: bool) -> &[Order] {
+        if is_bid {
+            &self.bids[..self.num_bids as usize]
+        } else {
+            &self.asks[..self.num_asks as usize]
+        }
+    }
+
+    /// Returns the best resting order on one side, if any.
+    pub fn best_order(&self, is_bid: bool) -> Option<&Order> {
+        self.orders(is_bid).first()
+    }
+
+    /// Finds an order by id. Returns its side and index.
+    pub fn find_order(&self, order_id: u64) -> Option<(bool, usize)> {
+        [true, false].into_iter().find_map(|is_bid| {
+            self.orders(is_bid)
+                .iter()
+                .position(|o| o.order_id == order_id)
+                .map(|index| (is_bid, index))
+        })
+    }
+
+    /// Inserts a resting order behind every order at the same or a better price.
+    /// Returns the id assigned to it.
+    pub fn insert_order(
+        &mut self,
+        is_bid: bool,
+        user: Pubkey,
+        price: u128,
+        base_asset_amount: u128,
+        max_ts: i64,
+    ) -> Result<u64> {
+        let order_id = self.next_order_id;
+        self.next_order_id = order_id.checked_add(1).ok_or(PerpError::MathOverflow)?;
+
+        let (orders, num_orders) = if is_bid {
+            (&mut self.bids, &mut self.num_bids)
+        } else {
+            (&mut self.asks, &mut self.num_asks)
+        };
+        let len = *num_orders as usize;
+        require_gt!(MAX_ORDERS_PER_SIDE, len, PerpError::OrderBookFull);
+
+        let index = orders[..len]
+            .iter()
+            .position(|o| {
+                if is_bid {
+                    o.price < price
+                } else {
+                    o.price > price
+                }
+            })
+            .unwrap_or(len);
+        orders.copy_within(index..len, index + 1);
+        orders[index] = Order {
+            price,
+            base_asset_amount,
+            user,
+            order_id,
+            max_ts,
+        };
+        *num_orders += 1;
+
+        Ok(order_id)
+    }
+
+    /// Removes the order at `index` on one side, preserving the order of the rest.
+    pub fn remove_order(&mut self, is_bid: bool, index: usize) -> Order {
+        let (orders, num_orders) = if is_bid {
+            (&mut self.bids, &mut self.num_bids)
+        } else {
+            (&mut self.asks, &mut self.num_asks)
+        };
+        let len = *num_orders as usize;
+
+        let order = orders[index];
+        orders.copy_within(index + 1..len, index);
+        orders[len - 1] = Order::default();
+        *num_orders -= 1;
+
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_interest_tracks_each_side() {
+        let mut market = Market::default();
+        market.update_open_interest(0, 0, 3 * PRECISION as i128, 300 * PRECISION).unwrap();
+        market.update_open_interest(0, 0, -2 * PRECISION as i128, 200 * PRECISION).unwrap();
+        assert_eq!(market.base_asset_amount_long, 3 * PRECISION as i128);
+        assert_eq!(market.base_asset_amount_short, -2 * PRECISION as i128);
+        assert_eq!(market.open_interest_base, 5 * PRECISION);
+
+        // A long flipping short leaves the long side and joins the short side
+        market
+            .update_open_interest(PRECISION as i128, 100 * PRECISION, -(PRECISION as i128), 90 * PRECISION)
+            .unwrap();
+        assert_eq!(market.base_asset_amount_long, 2 * PRECISION as i128);
+        assert_eq!(market.base_asset_amount_short, -3 * PRECISION as i128);
+        assert_eq!(market.quote_asset_amount_long, 200 * PRECISION);
+        assert_eq!(market.quote_asset_amount_short, 290 * PRECISION);
+        assert_eq!(market.open_interest_base, 5 * PRECISION);
+    }
+
+    #[test]
+    fn collected_fees_split_between_fee_pool_and_insurance() {
+        let mut market = Market {
+            insurance_fee_share: 200_000,
+            ..Default::default()
+        };
+        market.collect_fee(1_001).unwrap();
+
+        assert_eq!(market.total_fee, 1_001);
+        assert_eq!(market.insurance_fee_pool, 200);
+        assert_eq!(market.fee_pool, 801);
+    }
+
+    #[test]
+    fn deficits_are_socialized_across_the_charged_side() {
+        let mut market = Market {
+            base_asset_amount_long: 4 * PRECISION as i128,
+            ..Default::default()
+        };
+        market.absorb_deficit(2_000_000, true).unwrap();
+
+        assert_eq!(market.cumulative_loss_per_base_long, PRECISION / 2);
+        assert_eq!(market.cumulative_loss_per_base_short, 0);
+        assert_eq!(market.pending_adl_deficit_long, 0);
+    }
+
+    #[test]
+    fn deficits_are_queued_for_adl() {
+        // A side without open interest cannot absorb a socialized loss
+        let mut market = Market::default();
+        market.absorb_deficit(2_000_000, false).unwrap();
+        assert_eq!(market.pending_adl_deficit_short, 2_000_000);
+
+        let mut market = Market {
+            base_asset_amount_long: 4 * PRECISION as i128,
+            bankruptcy_mode: 1,
+            ..Default::default()
+        };
+        market.absorb_deficit(2_000_000, true).unwrap();
+        assert_eq!(market.cumulative_loss_per_base_long, 0);
+        assert_eq!(market.pending_adl_deficit_long, 2_000_000);
+    }
+
+    #[test]
+    fn adl_score_bound_is_the_side_average_pnl_ratio() {
+        let mut market = Market::default();
+        market.update_open_interest(0, 0, PRECISION as i128, 90 * PRECISION).unwrap();
+        market.update_open_interest(0, 0, PRECISION as i128, 110 * PRECISION).unwrap();
+        market.update_open_interest(0, 0, -(PRECISION as i128), 100 * PRECISION).unwrap();
+
+        // Longs entered at 100 on average and are up 10% at 110
+        assert_eq!(market.get_adl_score_bound(110 * PRECISION, true).unwrap(), PRECISION / 10);
+        // Shorts are losing, so the bound does not restrict them
+        assert_eq!(market.get_adl_score_bound(110 * PRECISION, false).unwrap(), 0);
+    }
+
+    #[test]
+    fn funding_imbalance_is_settled_against_the_fee_pool() {
+        let mut market = Market {
+            base_asset_amount_long: 3 * PRECISION as i128,
+            base_asset_amount_short: -(PRECISION as i128),
+            fee_pool: 5_000_000,
+            ..Default::default()
+        };
+
+        // Longs pay 3 and shorts receive 1; the surplus accrues to the fee pool
+        market.apply_funding_rate(PRECISION as i128, 3_600).unwrap();
+        assert_eq!(market.fee_pool, 7_000_000);
+
+        // Shorts pay 1 and longs are owed 3, drawn from the fee pool
+        market.apply_funding_rate(-(PRECISION as i128), 7_200).unwrap();
+        assert_eq!(market.fee_pool, 5_000_000);
+        assert_eq!(market.total_funding_paid, 4 * PRECISION);
+        assert_eq!(market.total_funding_received, 4 * PRECISION);
+    }
+
+    #[test]
+    fn unpaid_funding_is_drawn_from_the_fee_pool_then_socialized() {
+        let mut market = Market {
+            base_asset_amount_short: -(2 * PRECISION as i128),
+            fee_pool: 1_000_000,
+            total_funding_paid: 10 * PRECISION,
+            ..Default::default()
+        };
+        market.absorb_unpaid_funding(3_000_000, true).unwrap();
+
+        assert_eq!(market.fee_pool, 0);
+        assert_eq!(market.total_funding_paid, 7 * PRECISION);
+        // The remaining 2 is charged to the shorts who were credited it
+        assert_eq!(market.cumulative_loss_per_base_short, PRECISION);
+    }
+
+    #[test]
+    fn order_book_keeps_price_time_priority() {
+        let mut order_book = OrderBook::zeroed();
+        let user = Pubkey::new_unique();
+        order_book.insert_order(true, user, 100 * PRECISION, PRECISION, 0).unwrap();
+        order_book.insert_order(true, user, 101 * PRECISION, PRECISION, 0).unwrap();
+        order_book.insert_order(true, user, 100 * PRECISION, PRECISION, 0).unwrap();
+        order_book.insert_order(false, user, 105 * PRECISION, PRECISION, 0).unwrap();
+        order_book.insert_order(false, user, 104 * PRECISION, PRECISION, 0).unwrap();
+
+        let bid_ids: Vec<u64> = order_book.orders(true).iter().map(|o| o.order_id).collect();
+        assert_eq!(bid_ids, vec![1, 0, 2]);
+        assert_eq!(order_book.best_order(false).unwrap().order_id, 4);
+
+        // Removing the best bid promotes the earliest order at the next price
+        assert_eq!(order_book.remove_order(true, 0).order_id, 1);
+        assert_eq!(order_book.best_order(true).unwrap().order_id, 0);
+        assert_eq!(order_book.find_order(2), Some((true, 1)));
+        assert_eq!(order_book.find_order(1), None);
+    }
+
+    #[test]
+    fn limit_sizing_keeps_the_average_price_within_the_limit_after_the_spread() {
+        // Mark 100, with a stale mark TWAP 2% over the oracle TWAP widening the ask
+        let mut market = Market {
+            amm_base_asset_reserve: 1_000 * PRECISION,
+            amm_quote_asset_reserve: 1_000 * PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            base_spread: 1_000,
+            max_spread: 100_000,
+            last_oracle_price_twap: 100 * PRECISION,
+            last_mark_price_twap: 102 * PRECISION,
+            last_mark_price_twap_ts: 1,
+            last_mark_price: 100 * PRECISION,
+            funding_period: 3_600,
+            ..Default::default()
+        };
+        let limit_price = 103 * PRECISION;
+        let average_price = |market: &Market, base_asset_amount: u128| {
+            let mut market = *market;
+            let quote_asset_amount = market.swap_base_asset(base_asset_amount, TradeDirection::Long).unwrap();
+            quote_asset_amount * PRECISION / base_asset_amount
+        };
+
+        let stale_size = market.get_max_base_asset_amount_within_limit(limit_price, TradeDirection::Long).unwrap();
+        assert!(stale_size > 0);
+        assert!(average_price(&market, stale_size) <= limit_price);
+        assert!(average_price(&market, stale_size + PRECISION / 100) > limit_price);
+
+        // Once the mark TWAP catches up to the mark the divergence spread drops and more fits
+        market.update_mark_price_twap(10_000).unwrap();
+        let size = market.get_max_base_asset_amount_within_limit(limit_price, TradeDirection::Long).unwrap();
+        assert!(size > stale_size);
+        assert!(average_price(&market, size) <= limit_price);
+        assert!(average_price(&market, size + PRECISION / 100) > limit_price);
+    }
+
+    #[test]
+    fn scaling_reserves_keeps_the_mark_and_charges_the_fee_pool() {
+        let mut market = Market {
+            amm_base_asset_reserve: 1_000 * PRECISION,
+            amm_quote_asset_reserve: 1_000 * PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            base_asset_amount_long: 10 * PRECISION as i128,
+            fee_pool: 10_000_000,
+            ..Default::default()
+        };
+
+        // A cost the fee pool cannot cover is rejected before the reserves move
+        let mut underfunded = Market { fee_pool: 1_000_000, ..market };
+        assert!(underfunded.scale_reserves(2_000_000).is_err());
+        assert_eq!(underfunded.amm_base_asset_reserve, 1_000 * PRECISION);
+
+        market.scale_reserves(2_000_000).unwrap();
+        assert_eq!(market.amm_base_asset_reserve, 2_000 * PRECISION);
+        assert_eq!(market.amm_k_constant, 4_000_000 * PRECISION * PRECISION);
+        assert_eq!(market.get_mark_price().unwrap(), 100 * PRECISION);
+        // 4_925_865_700 rounded up against the pool
+        assert_eq!(market.fee_pool, 5_074_134);
+    }
+
+    #[test]
+    fn formulaic_adjustment_spends_surplus_and_shrinks_on_deficit() {
+        let market = Market {
+            amm_base_asset_reserve: 1_000 * PRECISION,
+            amm_quote_asset_reserve: 1_000 * PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            base_asset_amount_long: 10 * PRECISION as i128,
+            amm_fee_pool_target: 50_000_000,
+            max_amm_adjustment_ratio: 10_000,
+            max_k_constant: u128::MAX,
+            ..Default::default()
+        };
+
+        // Above target the peg moves 1% toward an oracle TWAP of 110 and the reserves deepen 1%
+        let mut surplus = Market { fee_pool: 100_000_000, ..market };
+        surplus.apply_formulaic_amm_adjustment(110 * PRECISION).unwrap();
+        assert_eq!(surplus.peg_multiplier, 101 * PEG_PRECISION);
+        assert_eq!(surplus.amm_base_asset_reserve, 1_010 * PRECISION);
+        assert_eq!(surplus.get_mark_price().unwrap(), 101 * PRECISION);
+        // Repeg cost 9_900_991 and k cost 98_040
+        assert_eq!(surplus.fee_pool, 90_000_969);
+
+        // Below target the reserves shrink 1%, which nets longs a worse exit and the pool a profit
+        let mut deficit = Market { fee_pool: 10_000_000, ..market };
+        deficit.apply_formulaic_amm_adjustment(110 * PRECISION).unwrap();
+        assert_eq!(deficit.peg_multiplier, 100 * PEG_PRECISION);
+        assert_eq!(deficit.amm_base_asset_reserve, 990 * PRECISION);
+        assert_eq!(deficit.fee_pool, 10_099_010);
+
+        // A zero ratio disables the adjustment
+        let mut disabled = Market { fee_pool: 100_000_000, max_amm_adjustment_ratio: 0, ..market };
+        disabled.apply_formulaic_amm_adjustment(110 * PRECISION).unwrap();
+        assert_eq!(disabled.peg_multiplier, 100 * PEG_PRECISION);
+        assert_eq!(disabled.amm_base_asset_reserve, 1_000 * PRECISION);
+    }
+}
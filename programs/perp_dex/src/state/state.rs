@@ -1,4 +1,5 @@
 use anchor_lang::prelude::*;
+use crate::error::PerpError;
 
 /// Global state for the perpetuals DEX.
 #[account]
@@ -18,6 +19,49 @@ pub struct State {
 
     /// Is the program paused (e.g., for upgrades).
     pub paused: bool,
+
+    /// Share accounting for the insurance fund vault.
+    pub insurance_fund: InsuranceFund,
+}
+
+/// Share accounting for the protocol insurance fund.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct InsuranceFund {
+    /// Total shares issued to stakers.
+    pub total_shares: u128,
+
+    /// Seconds a staker must wait between requesting and completing an unstake.
+    pub unstaking_period: i64,
+
+    /// Incremented whenever deficits wipe out the vault, voiding all shares issued before.
+    pub shares_epoch: u64,
+
+    /// Shares held by the protocol for the balance the vault held before the first stake.
+    /// Included in `total_shares` and never redeemed.
+    pub protocol_shares: u128,
+}
+
+impl InsuranceFund {
+    /// Voids the outstanding shares once the vault has been drained to zero, so new stakes
+    /// are not priced against shares that no longer hold any value.
+    pub fn rebase_if_depleted(&mut self, vault_balance: u64) -> Result<()> {
+        if vault_balance == 0 && self.total_shares > 0 {
+            self.total_shares = 0;
+            self.protocol_shares = 0;
+            self.shares_epoch = self.shares_epoch.checked_add(1).ok_or(PerpError::MathOverflow)?;
+        }
+
+        Ok(())
+    }
+
+    /// Issues the protocol shares for a balance already in the vault when no shares are
+    /// outstanding, such as settled insurance fees, so the first staker cannot claim it.
+    pub fn seed_protocol_shares(&mut self, vault_balance: u64) {
+        if self.total_shares == 0 && vault_balance > 0 {
+            self.protocol_shares = vault_balance as u128;
+            self.total_shares = self.protocol_shares;
+        }
+    }
 }
 
 impl State {
@@ -28,5 +72,9 @@ impl State {
         + 1                     // bump
         + 2                     // number_of_markets
         + 1                     // paused
-        + 200;                  // padding for future upgrades
+        + 16                    // insurance_fund.total_shares
+        + 8                     // insurance_fund.unstaking_period
+        + 8                     // insurance_fund.shares_epoch
+        + 16                    // insurance_fund.protocol_shares
+        + 152;                  // padding for future upgrades
 }
@@ -1,7 +1,15 @@
 use anchor_lang::prelude::*;
 use bytemuck::{Pod, Zeroable};
 
-use crate::state::constants::{MAX_POSITIONS, PRECISION};
+use crate::state::constants::{
+    MAX_LIMIT_ORDERS, MAX_POSITIONS, MAX_TRIGGER_ORDERS, MAX_TWAP_ORDERS, PRECISION,
+    RATIO_PRECISION,
+};
+use crate::state::market::Market;
+use crate::math::margin::{
+    calculate_margin, collateral_to_quote, quote_to_collateral, quote_to_collateral_round_up,
+    MarginRequirementType, MarketOracle, MarketOracleMap,
+};
 use crate::error::PerpError;
 
 /// A user's position in a single market.
@@ -23,10 +31,118 @@ pub struct Position {
 
     /// Last timestamp funding was settled.
     pub last_settled_funding_ts: i64,
+
+    /// Cumulative socialized loss per base of the position's side at last settlement.
+    pub last_cumulative_loss_per_base: u128,
+
+    /// Base asset amount of resting bids on the order book.
+    pub open_bids: u128,
+
+    /// Base asset amount of resting asks on the order book.
+    pub open_asks: u128,
+
+    /// PnL realized by reductions of the position (in price precision).
+    pub realized_pnl: i128,
+
+    /// Average price the open position was entered at (in price precision).
+    pub average_entry_price: u128,
 }
 
 impl Position {
-    /// Calculates the unrealized PnL for the position.
+    /// Returns true if the slot holds neither a position nor resting orders.
+    pub fn is_available(&self) -> bool {
+        self.base_asset_amount == 0 && self.open_bids == 0 && self.open_asks == 0
+    }
+
+    /// Clears stale state from a slot left with no position or orders,
+    /// keeping the realized PnL for reporting until the slot is reused for another market.
+    pub fn clear_if_available(&mut self) {
+        if self.is_available() {
+            *self = Position {
+                market_index: self.market_index,
+                realized_pnl: self.realized_pnl,
+                ..Default::default()
+            };
+        }
+    }
+
+    /// Recomputes the average entry price from the cost basis and size.
+    fn update_average_entry_price(&mut self) -> Result<()> {
+        let position_size = self.base_asset_amount.unsigned_abs();
+        self.average_entry_price = if position_size == 0 {
+            0
+        } else {
+            self.quote_asset_amount
+                .checked_mul(PRECISION)
+                .and_then(|p| p.checked_div(position_size))
+                .ok_or(PerpError::MathOverflow)?
+        };
+
+        Ok(())
+    }
+
+    /// Returns the position size if every resting order on the worse side were filled.
+    pub fn get_worst_case_base_asset_amount(&self) -> Result<u128> {
+        let all_bids_filled = self
+            .base_asset_amount
+            .checked_add(self.open_bids as i128)
+            .ok_or(PerpError::MathOverflow)?;
+        let all_asks_filled = self
+            .base_asset_amount
+            .checked_sub(self.open_asks as i128)
+            .ok_or(PerpError::MathOverflow)?;
+
+        Ok(all_bids_filled
+            .unsigned_abs()
+            .max(all_asks_filled.unsigned_abs()))
+    }
+
+    /// Applies a fill of `base_asset_amount` (positive buys, negative sells) for
+    /// `quote_asset_amount`. The part opposing the position is reduced first so a fill that
+    /// flips the position realizes PnL on the closed part and opens the rest at the fill price.
+    /// Returns the realized PnL (in price precision).
+    pub fn apply_fill(
+        &mut self,
+        base_asset_amount: i128,
+        quote_asset_amount: u128,
+    ) -> Result<i128> {
+        let fill_size = base_asset_amount.unsigned_abs();
+        let mut open_size = fill_size;
+        let mut open_quote = quote_asset_amount;
+        let mut pnl = 0;
+
+        if self.base_asset_amount != 0 && (self.base_asset_amount > 0) != (base_asset_amount > 0) {
+            let reduce_size = fill_size.min(self.base_asset_amount.unsigned_abs());
+            let reduce_quote = quote_asset_amount
+                .checked_mul(reduce_size)
+                .and_then(|q| q.checked_div(fill_size))
+                .ok_or(PerpError::MathOverflow)?;
+            pnl = self.reduce(reduce_size, reduce_quote)?;
+            open_size -= reduce_size;
+            open_quote -= reduce_quote;
+        }
+
+        if open_size > 0 {
+            let base_delta = if base_asset_amount > 0 {
+                open_size as i128
+            } else {
+                -(open_size as i128)
+            };
+            self.base_asset_amount = self
+                .base_asset_amount
+                .checked_add(base_delta)
+                .ok_or(PerpError::MathOverflow)?;
+            self.quote_asset_amount = self
+                .quote_asset_amount
+                .checked_add(open_quote)
+                .ok_or(PerpError::MathOverflow)?;
+            self.update_average_entry_price()?;
+        }
+
+        Ok(pnl)
+    }
+
+    /// Calculates the unrealized PnL for the position (in price precision).
     pub fn get_unrealized_pnl(&self, mark_price: u128) -> Result<i128> {
         if self.base_asset_amount == 0 {
             return Ok(0);
@@ -35,15 +151,13 @@ impl Position {
         // Value of position at current mark price
         let current_value = self
             .base_asset_amount
-            .abs()
+            .unsigned_abs()
             .checked_mul(mark_price)
-            .ok_or(PerpError::MathOverflow)?;
+            .and_then(|v| v.checked_div(PRECISION))
+            .ok_or(PerpError::MathOverflow)? as i128;
 
         // Value of position at entry
-        let entry_value = self
-            .quote_asset_amount
-            .checked_mul(PRECISION)
-            .ok_or(PerpError::MathOverflow)?;
+        let entry_value = self.quote_asset_amount as i128;
 
         let pnl = if self.base_asset_amount > 0 {
             // Long position
@@ -54,8 +168,427 @@ impl Position {
         }
         .ok_or(PerpError::MathOverflow)?;
 
-        Ok(pnl as i128)
+        Ok(pnl)
     }
+
+    /// Reduces the position by `base_asset_amount`, releasing a proportional share of its cost basis.
+    /// Returns the realized PnL given the quote asset received or paid to exit (in price precision).
+    pub fn reduce(&mut self, base_asset_amount: u128, exit_quote_asset_amount: u128) -> Result<i128> {
+        let position_size = self.base_asset_amount.unsigned_abs();
+        require_gte!(position_size, base_asset_amount, PerpError::InvalidAmount);
+
+        let closed_cost_basis = self
+            .quote_asset_amount
+            .checked_mul(base_asset_amount)
+            .and_then(|q| q.checked_div(position_size))
+            .ok_or(PerpError::MathOverflow)?;
+
+        let pnl = if self.base_asset_amount > 0 {
+            (exit_quote_asset_amount as i128).checked_sub(closed_cost_basis as i128)
+        } else {
+            (closed_cost_basis as i128).checked_sub(exit_quote_asset_amount as i128)
+        }
+        .ok_or(PerpError::MathOverflow)?;
+
+        let base_delta = if self.base_asset_amount > 0 {
+            -(base_asset_amount as i128)
+        } else {
+            base_asset_amount as i128
+        };
+        self.base_asset_amount = self
+            .base_asset_amount
+            .checked_add(base_delta)
+            .ok_or(PerpError::MathOverflow)?;
+        self.quote_asset_amount = self
+            .quote_asset_amount
+            .checked_sub(closed_cost_basis)
+            .ok_or(PerpError::MathOverflow)?;
+        self.realized_pnl = self
+            .realized_pnl
+            .checked_add(pnl)
+            .ok_or(PerpError::MathOverflow)?;
+        self.update_average_entry_price()?;
+
+        Ok(pnl)
+    }
+
+    /// Calculates the socialized loss charged to the position's side since it last settled (in price precision).
+    pub fn get_pending_socialized_loss(&self, market: &Market) -> Result<u128> {
+        if self.base_asset_amount == 0 {
+            return Ok(0);
+        }
+
+        market
+            .get_cumulative_loss_per_base(self.base_asset_amount > 0)
+            .checked_sub(self.last_cumulative_loss_per_base)
+            .and_then(|l| l.checked_mul(self.base_asset_amount.unsigned_abs()))
+            .and_then(|l| l.checked_div(PRECISION))
+            .ok_or(PerpError::MathOverflow.into())
+    }
+
+    /// Calculates the funding accrued on the position's side since it last settled.
+    /// Positive means the position pays, negative means it receives (in price precision).
+    pub fn get_pending_funding_payment(&self, market: &Market) -> Result<i128> {
+        if self.base_asset_amount == 0 {
+            return Ok(0);
+        }
+
+        market
+            .get_cumulative_funding_rate(self.base_asset_amount > 0)
+            .checked_sub(self.last_cumulative_funding_rate)
+            .and_then(|r| r.checked_mul(self.base_asset_amount))
+            .and_then(|p| p.checked_div(PRECISION as i128))
+            .ok_or(PerpError::MathOverflow.into())
+    }
+
+    /// Snapshots the market's funding and socialized loss indices for the position's current side.
+    /// Must follow a settlement against `market` so nothing pending is skipped.
+    pub fn update_market_indices(&mut self, market: &Market) {
+        let is_long = self.base_asset_amount > 0;
+        self.last_cumulative_funding_rate = market.get_cumulative_funding_rate(is_long);
+        self.last_cumulative_loss_per_base = market.get_cumulative_loss_per_base(is_long);
+    }
+}
+
+/// Which side of the trigger price fires a trigger order.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerCondition {
+    /// Fires once the price is at or above the trigger price.
+    Above,
+
+    /// Fires once the price is at or below the trigger price.
+    Below,
+}
+
+/// Which price a trigger order is compared against.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerSource {
+    Oracle,
+    Mark,
+}
+
+/// How an order treats the part of its size it cannot fill within its limit price.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OrderType {
+    /// Against the vAMM the whole size fills within the limit or the trade fails;
+    /// on the order book the unfilled remainder rests.
+    Limit,
+
+    /// Fill as much as possible within the limit and cancel the rest.
+    ImmediateOrCancel,
+
+    /// Fill the whole size within the limit or fail.
+    FillOrKill,
+
+    /// Only rest on the order book; fail if the order would take liquidity.
+    PostOnly,
+}
+
+/// Parameters for trading against the vAMM with `open_position`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct OrderParams {
+    /// The amount of base asset to buy (positive) or sell (negative).
+    pub base_asset_amount: i128,
+    /// Worst average price accepted.
+    pub limit_price: u128,
+    /// Only reduce the existing position: sizes beyond it are clipped, increases are rejected.
+    pub reduce_only: bool,
+    pub order_type: OrderType,
+    /// Timestamp after which the order is rejected.
+    pub max_ts: Option<i64>,
+}
+
+/// Parameters for placing a trigger order.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct TriggerOrderParams {
+    pub market_index: u16,
+    pub trigger_price: u128,
+    pub condition: TriggerCondition,
+    pub source: TriggerSource,
+    /// Most base the order reduces the position by.
+    pub base_asset_amount: u128,
+    /// Worst exit price accepted when the order executes.
+    pub limit_price: u128,
+    /// Timestamp after which the order can no longer execute.
+    pub max_ts: Option<i64>,
+}
+
+/// Parameters for placing a TWAP order.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct TwapOrderParams {
+    pub market_index: u16,
+    /// The total amount of base asset to buy (positive) or sell (negative).
+    pub base_asset_amount: i128,
+    /// Base traded per slice.
+    pub slice_base_asset_amount: u128,
+    /// Seconds between slices.
+    pub interval: i64,
+    /// Worst average price accepted for each slice.
+    pub limit_price: u128,
+    /// Timestamp after which no further slices execute.
+    pub max_ts: Option<i64>,
+}
+
+/// A reduce-only stop-loss or take-profit order that closes part of a position
+/// once its trigger condition is met.
+#[zero_copy]
+#[repr(C)]
+#[derive(Default, Pod, Zeroable)]
+pub struct TriggerOrder {
+    /// Price the condition is evaluated against (in price precision).
+    pub trigger_price: u128,
+
+    /// Worst exit price accepted when the order executes (in price precision).
+    pub limit_price: u128,
+
+    /// Most base the order reduces the position by.
+    pub base_asset_amount: u128,
+
+    /// Market index the order belongs to.
+    pub market_index: u16,
+
+    /// `TriggerCondition` of the order.
+    pub condition: u8,
+
+    /// `TriggerSource` of the order.
+    pub source: u8,
+
+    /// Whether the slot holds a live order.
+    pub is_active: bool,
+
+    /// Whether the order reduces a long position, taken from the position it was placed against.
+    pub reduces_long: bool,
+
+    pub _padding: [u8; 2],
+
+    /// Timestamp after which the order can no longer execute, zero for no expiry.
+    pub max_ts: i64,
+}
+
+impl TriggerOrder {
+    /// Returns the order's trigger condition.
+    pub fn get_condition(&self) -> Result<TriggerCondition> {
+        match self.condition {
+            0 => Ok(TriggerCondition::Above),
+            1 => Ok(TriggerCondition::Below),
+            _ => Err(PerpError::InvalidCalculation.into()),
+        }
+    }
+
+    /// Returns the order's trigger price source.
+    pub fn get_source(&self) -> Result<TriggerSource> {
+        match self.source {
+            0 => Ok(TriggerSource::Oracle),
+            1 => Ok(TriggerSource::Mark),
+            _ => Err(PerpError::InvalidCalculation.into()),
+        }
+    }
+
+    /// Returns true if `price` satisfies the order's trigger condition.
+    pub fn is_triggered(&self, price: u128) -> Result<bool> {
+        Ok(match self.get_condition()? {
+            TriggerCondition::Above => price >= self.trigger_price,
+            TriggerCondition::Below => price <= self.trigger_price,
+        })
+    }
+}
+
+/// A resting limit order filled by keepers against the vAMM once its price is reachable.
+#[zero_copy]
+#[repr(C)]
+#[derive(Default, Pod, Zeroable)]
+pub struct LimitOrder {
+    /// Limit price (in price precision).
+    pub price: u128,
+
+    /// Unfilled base asset amount.
+    pub base_asset_amount: u128,
+
+    /// Id of the order, unique per user.
+    pub order_id: u64,
+
+    /// Timestamp after which the order can no longer fill, zero for no expiry.
+    pub max_ts: i64,
+
+    /// Market index the order belongs to.
+    pub market_index: u16,
+
+    /// Whether the order buys or sells.
+    pub is_long: bool,
+
+    /// Whether the slot holds a live order.
+    pub is_active: bool,
+
+    pub _padding: [u8; 12],
+}
+
+/// An order worked against the vAMM in equal slices, one per interval.
+#[zero_copy]
+#[repr(C)]
+#[derive(Default, Pod, Zeroable)]
+pub struct TwapOrder {
+    /// Base still to be traded.
+    pub base_asset_amount_remaining: u128,
+
+    /// Base traded per slice.
+    pub slice_base_asset_amount: u128,
+
+    /// Worst average price accepted for each slice (in price precision).
+    pub limit_price: u128,
+
+    /// Seconds between slices.
+    pub interval: i64,
+
+    /// Earliest timestamp the next slice can execute at.
+    pub next_slice_ts: i64,
+
+    /// Timestamp after which no further slices execute, zero for no expiry.
+    pub max_ts: i64,
+
+    /// Market index the order belongs to.
+    pub market_index: u16,
+
+    /// Whether the order buys or sells.
+    pub is_long: bool,
+
+    /// Whether the slot holds a live order.
+    pub is_active: bool,
+
+    pub _padding: [u8; 4],
+}
+
+impl TwapOrder {
+    /// Returns the signed base of the next slice, the last one taking whatever remains.
+    pub fn get_next_slice(&self) -> i128 {
+        let slice = self
+            .slice_base_asset_amount
+            .min(self.base_asset_amount_remaining) as i128;
+        if self.is_long {
+            slice
+        } else {
+            -slice
+        }
+    }
+}
+
+/// A staker's share of the insurance fund.
+#[account]
+#[derive(Default)]
+pub struct InsuranceFundStake {
+    /// The authority (owner) of this stake.
+    pub authority: Pubkey,
+
+    /// The PDA bump.
+    pub bump: u8,
+
+    /// Insurance fund shares owned by the staker.
+    pub shares: u128,
+
+    /// Shares requested for removal, zero if no request is pending.
+    pub last_withdraw_request_shares: u128,
+
+    /// Value of the requested shares at request time (in collateral precision).
+    pub last_withdraw_request_value: u64,
+
+    /// Timestamp of the pending removal request.
+    pub last_withdraw_request_ts: i64,
+
+    /// The insurance fund's `shares_epoch` the shares were issued in.
+    pub shares_epoch: u64,
+}
+
+impl InsuranceFundStake {
+    /// Total size of the account.
+    pub const LEN: usize = 8    // discriminator
+        + 32                    // authority
+        + 1                     // bump
+        + 16                    // shares
+        + 16                    // last_withdraw_request_shares
+        + 8                     // last_withdraw_request_value
+        + 8                     // last_withdraw_request_ts
+        + 8;                    // shares_epoch
+
+    /// Drops shares and any pending removal request issued before the fund's current
+    /// `shares_epoch`, as a depleted vault left them worthless.
+    pub fn sync_shares_epoch(&mut self, shares_epoch: u64) {
+        if self.shares_epoch != shares_epoch {
+            *self = InsuranceFundStake {
+                authority: self.authority,
+                bump: self.bump,
+                shares_epoch,
+                ..Default::default()
+            };
+        }
+    }
+}
+
+/// A taker's trade intent, open to JIT maker fills for `JIT_AUCTION_DURATION_SLOTS`
+/// before the unfilled remainder settles against the vAMM.
+#[account]
+#[derive(Default)]
+pub struct TakerAuction {
+    /// The authority of the taker, refunded the rent when the auction settles or is cancelled.
+    pub authority: Pubkey,
+
+    /// The taker's user account.
+    pub user: Pubkey,
+
+    /// The PDA bump.
+    pub bump: u8,
+
+    /// Market index the trade is in.
+    pub market_index: u16,
+
+    /// Unfilled base asset amount. Positive buys, negative sells.
+    pub base_asset_amount: i128,
+
+    /// Worst price the taker accepts from makers and the vAMM.
+    pub limit_price: u128,
+
+    /// Slot the auction started in.
+    pub start_slot: u64,
+
+    /// Timestamp after which the auction can no longer fill, zero for no expiry.
+    pub max_ts: i64,
+}
+
+impl TakerAuction {
+    /// Total size of the account.
+    pub const LEN: usize = 8    // discriminator
+        + 32                    // authority
+        + 32                    // user
+        + 1                     // bump
+        + 2                     // market_index
+        + 16                    // base_asset_amount
+        + 16                    // limit_price
+        + 8                     // start_slot
+        + 8;                    // max_ts
+}
+
+/// A position in the original user account layout, read by `migrate_user`.
+#[zero_copy]
+#[repr(C)]
+#[derive(Default, Pod, Zeroable)]
+pub struct LegacyPosition {
+    pub market_index: u16,
+    pub base_asset_amount: i128,
+    pub quote_asset_amount: u128,
+    pub last_cumulative_funding_rate: i128,
+    pub last_settled_funding_ts: i64,
+}
+
+/// The original user account layout, read by `migrate_user`.
+#[zero_copy]
+#[repr(C)]
+#[derive(Pod, Zeroable)]
+pub struct LegacyUser {
+    pub authority: Pubkey,
+    pub bump: u8,
+    pub initialized: bool,
+    pub operation_lock: bool,
+    pub collateral: u64,
+    pub positions: [LegacyPosition; MAX_POSITIONS],
+    pub _padding: [u8; 256],
 }
 
 /// A user's account storing collateral and positions.
@@ -74,6 +607,9 @@ pub struct User {
     /// Prevents re-entrant CPIs during sensitive operations.
     pub operation_lock: bool,
 
+    /// Whether the user may fill taker auctions as a JIT maker.
+    pub is_jit_maker: bool,
+
     // Collateral
     /// Total collateral deposited (in collateral precision).
     pub collateral: u64,
@@ -81,16 +617,59 @@ pub struct User {
     // Positions
     pub positions: [Position; MAX_POSITIONS],
 
+    // Orders
+    pub trigger_orders: [TriggerOrder; MAX_TRIGGER_ORDERS],
+
+    /// TWAP orders worked by keepers.
+    pub twap_orders: [TwapOrder; MAX_TWAP_ORDERS],
+
+    /// Resting limit orders filled by keepers against the vAMM.
+    pub limit_orders: [LimitOrder; MAX_LIMIT_ORDERS],
+
+    /// Id assigned to the user's next limit order.
+    pub next_limit_order_id: u64,
+
     /// Padding for future upgrades.
     pub _padding: [u8; 256],
 }
 
 impl User {
-    /// Finds a mutable reference to an existing position in a specific market.
+    /// Fills a zeroed user from one in the original layout. The original layout never recorded
+    /// funding or socialized loss snapshots, so the caller must snapshot each position against
+    /// its market's current indexes before it is settled.
+    pub fn migrate_from(&mut self, legacy_user: &LegacyUser) -> Result<()> {
+        self.authority = legacy_user.authority;
+        self.bump = legacy_user.bump;
+        self.initialized = legacy_user.initialized;
+        self.collateral = legacy_user.collateral;
+
+        for (position, legacy_position) in self.positions.iter_mut().zip(&legacy_user.positions) {
+            *position = Position {
+                market_index: legacy_position.market_index,
+                base_asset_amount: legacy_position.base_asset_amount,
+                quote_asset_amount: legacy_position.quote_asset_amount,
+                last_settled_funding_ts: legacy_position.last_settled_funding_ts,
+                ..Default::default()
+            };
+            position.update_average_entry_price()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the slot of the user's active limit order `order_id`.
+    pub fn find_limit_order_index(&self, order_id: u64) -> Result<usize> {
+        self.limit_orders
+            .iter()
+            .position(|o| o.is_active && o.order_id == order_id)
+            .ok_or(PerpError::OrderNotFound.into())
+    }
+
+    /// Finds a mutable reference to an existing position or resting orders in a specific market.
     pub fn find_position_mut(&mut self, market_index: u16) -> Result<&mut Position> {
         self.positions
             .iter_mut()
-            .find(|p| p.market_index == market_index && p.base_asset_amount != 0)
+            .find(|p| p.market_index == market_index && !p.is_available())
             .ok_or(PerpError::PositionNotFound.into())
     }
 
@@ -102,11 +681,323 @@ impl User {
         }
 
         // Or find an empty slot to create a new one
-        if let Some(pos) = self.positions.iter_mut().find(|p| p.base_asset_amount == 0) {
-            pos.market_index = market_index;
+        if let Some(pos) = self.positions.iter_mut().find(|p| p.is_available()) {
+            *pos = Position {
+                market_index,
+                ..Default::default()
+            };
             return Ok(pos);
         }
 
         Err(PerpError::InvalidMarketIndex.into())
     }
+
+    /// Charges the position in `market` its pending socialized loss and re-snapshots the loss index.
+    /// Collateral is floored at zero; the part of the loss it cannot cover is charged back to the
+    /// position's side as a new deficit.
+    pub fn settle_socialized_loss(&mut self, market: &mut Market) -> Result<()> {
+        let Some(position) = self
+            .positions
+            .iter_mut()
+            .find(|p| p.market_index == market.market_index)
+        else {
+            return Ok(());
+        };
+
+        let is_long = position.base_asset_amount > 0;
+        let loss = quote_to_collateral(position.get_pending_socialized_loss(market)?)?;
+        position.last_cumulative_loss_per_base = market.get_cumulative_loss_per_base(is_long);
+
+        let shortfall = loss.saturating_sub(self.collateral);
+        self.collateral = self.collateral.saturating_sub(loss);
+        market.absorb_deficit(shortfall, is_long)
+    }
+
+    /// Settles the pending funding of the position in `market` into collateral and
+    /// re-snapshots the funding index. Payments round up and receipts round down so the
+    /// vault never pays out more than the market accounted for. Collateral is floored at zero;
+    /// the part of a payment it cannot cover is booked against the market as unpaid funding.
+    pub fn settle_funding_payment(&mut self, market: &mut Market) -> Result<()> {
+        let Some(position) = self
+            .positions
+            .iter_mut()
+            .find(|p| p.market_index == market.market_index)
+        else {
+            return Ok(());
+        };
+
+        let is_long = position.base_asset_amount > 0;
+        let funding_payment = position.get_pending_funding_payment(market)?;
+        position.last_cumulative_funding_rate = market.get_cumulative_funding_rate(is_long);
+        position.last_settled_funding_ts = market.last_funding_ts;
+
+        if funding_payment > 0 {
+            let funding_payment = quote_to_collateral_round_up(funding_payment.unsigned_abs())?;
+            let unpaid = funding_payment.saturating_sub(self.collateral);
+            self.collateral = self.collateral.saturating_sub(funding_payment);
+            market.absorb_unpaid_funding(unpaid, is_long)?;
+        } else {
+            let funding_receipt = quote_to_collateral(funding_payment.unsigned_abs())?;
+            self.collateral = self
+                .collateral
+                .checked_add(funding_receipt)
+                .ok_or(PerpError::MathOverflow)?;
+        }
+
+        Ok(())
+    }
+
+    /// Settles realized PnL (in price precision) into collateral, flooring collateral at zero.
+    /// Returns the part of a loss the collateral could not cover (in collateral precision).
+    pub fn settle_realized_pnl(&mut self, pnl: i128) -> Result<u64> {
+        let pnl_collateral = quote_to_collateral(pnl.unsigned_abs())?;
+
+        if pnl >= 0 {
+            self.collateral = self
+                .collateral
+                .checked_add(pnl_collateral)
+                .ok_or(PerpError::MathOverflow)?;
+            return Ok(0);
+        }
+
+        let shortfall = pnl_collateral.saturating_sub(self.collateral);
+        self.collateral = self.collateral.saturating_sub(pnl_collateral);
+
+        Ok(shortfall)
+    }
+
+    /// Computes account equity: collateral plus unrealized PnL at oracle price,
+    /// minus unsettled funding and socialized losses.
+    /// Positive PnL is reduced by each market's `unrealized_pnl_haircut` (in price precision).
+    pub fn equity(&self, market_oracle_map: &MarketOracleMap) -> Result<i128> {
+        let mut equity = collateral_to_quote(self.collateral)? as i128;
+
+        for position in self.positions.iter().filter(|p| p.base_asset_amount != 0) {
+            let MarketOracle { market, oracle_price } = market_oracle_map
+                .get(&position.market_index)
+                .ok_or(PerpError::MarketAccountMissing)?;
+
+            let mut unrealized_pnl = position.get_unrealized_pnl(*oracle_price)?;
+            if unrealized_pnl > 0 {
+                let haircut = unrealized_pnl
+                    .checked_mul(market.unrealized_pnl_haircut as i128)
+                    .and_then(|h| h.checked_div(RATIO_PRECISION as i128))
+                    .ok_or(PerpError::MathOverflow)?;
+                unrealized_pnl = unrealized_pnl
+                    .checked_sub(haircut)
+                    .ok_or(PerpError::MathOverflow)?;
+            }
+
+            let pending_funding = position.get_pending_funding_payment(market)?;
+            let pending_socialized_loss = position.get_pending_socialized_loss(market)? as i128;
+
+            equity = equity
+                .checked_add(unrealized_pnl)
+                .and_then(|e| e.checked_sub(pending_funding))
+                .and_then(|e| e.checked_sub(pending_socialized_loss))
+                .ok_or(PerpError::MathOverflow)?;
+        }
+
+        Ok(equity)
+    }
+
+    /// Computes equity in excess of the margin requirement of the given type (in price precision).
+    /// A negative value means the account is below that requirement.
+    pub fn free_collateral(
+        &self,
+        market_oracle_map: &MarketOracleMap,
+        requirement_type: MarginRequirementType,
+    ) -> Result<i128> {
+        let margin = calculate_margin(self, market_oracle_map, requirement_type)?;
+
+        margin
+            .equity
+            .checked_sub(margin.margin_requirement as i128)
+            .ok_or(PerpError::MathOverflow.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_user(collateral: u64, quote_asset_amount: u128) -> User {
+        let mut user = User::zeroed();
+        user.collateral = collateral;
+        user.positions[0].base_asset_amount = PRECISION as i128;
+        user.positions[0].quote_asset_amount = quote_asset_amount;
+        user
+    }
+
+    fn oracle_map(market: Market, oracle_price: u128) -> MarketOracleMap {
+        let mut market_oracle_map = MarketOracleMap::new();
+        market_oracle_map.insert(market.market_index, MarketOracle { market, oracle_price });
+        market_oracle_map
+    }
+
+    #[test]
+    fn equity_haircuts_gains_and_charges_pending_funding() {
+        let market = Market {
+            unrealized_pnl_haircut: 500_000,
+            cumulative_funding_rate_long: 2 * PRECISION as i128,
+            ..Default::default()
+        };
+        let user = long_user(10_000_000, 100 * PRECISION);
+
+        // 10 + 10 * 50% - 2
+        assert_eq!(user.equity(&oracle_map(market, 110 * PRECISION)).unwrap(), 13 * PRECISION as i128);
+        // Losses are not haircut: 10 - 10 - 2
+        assert_eq!(user.equity(&oracle_map(market, 90 * PRECISION)).unwrap(), -2 * PRECISION as i128);
+    }
+
+    #[test]
+    fn equity_skips_settled_funding() {
+        let market = Market {
+            cumulative_funding_rate_long: 2 * PRECISION as i128,
+            ..Default::default()
+        };
+        let mut user = long_user(10_000_000, 100 * PRECISION);
+        user.positions[0].update_market_indices(&market);
+
+        assert_eq!(user.equity(&oracle_map(market, 100 * PRECISION)).unwrap(), 10 * PRECISION as i128);
+    }
+
+    #[test]
+    fn socialized_loss_settles_once_and_resocializes_the_shortfall() {
+        let mut market = Market {
+            base_asset_amount_long: 2 * PRECISION as i128,
+            cumulative_loss_per_base_long: PRECISION / 2,
+            ..Default::default()
+        };
+        let mut user = long_user(300_000, 100 * PRECISION);
+
+        user.settle_socialized_loss(&mut market).unwrap();
+        assert_eq!(user.collateral, 0);
+        assert_eq!(user.positions[0].last_cumulative_loss_per_base, PRECISION / 2);
+        // The 0.2 the user could not cover is spread over the 2 long base
+        assert_eq!(market.cumulative_loss_per_base_long, PRECISION / 2 + PRECISION / 10);
+
+        // The settled loss is not charged twice, only the user's share of the shortfall
+        user.collateral = 1_000_000;
+        user.settle_socialized_loss(&mut market).unwrap();
+        assert_eq!(user.collateral, 900_000);
+    }
+
+    #[test]
+    fn migrated_positions_are_not_charged_the_market_history() {
+        let market = Market {
+            cumulative_funding_rate_long: 5 * PRECISION as i128,
+            cumulative_loss_per_base_long: 3 * PRECISION,
+            ..Default::default()
+        };
+        let mut legacy_user = LegacyUser::zeroed();
+        legacy_user.collateral = 10_000_000;
+        legacy_user.positions[0].base_asset_amount = PRECISION as i128;
+        legacy_user.positions[0].quote_asset_amount = 100 * PRECISION;
+
+        let mut user = User::zeroed();
+        user.migrate_from(&legacy_user).unwrap();
+        user.positions[0].update_market_indices(&market);
+
+        assert_eq!(user.positions[0].average_entry_price, 100 * PRECISION);
+        assert_eq!(user.positions[0].get_pending_funding_payment(&market).unwrap(), 0);
+        assert_eq!(user.positions[0].get_pending_socialized_loss(&market).unwrap(), 0);
+    }
+
+    #[test]
+    fn funding_settles_from_the_cumulative_index() {
+        let mut market = Market {
+            base_asset_amount_long: PRECISION as i128,
+            base_asset_amount_short: -(PRECISION as i128),
+            ..Default::default()
+        };
+        let mut long = long_user(10_000_000, 100 * PRECISION);
+        let mut short = User::zeroed();
+        short.collateral = 10_000_000;
+        short.positions[0].base_asset_amount = -(PRECISION as i128);
+        short.positions[0].quote_asset_amount = 100 * PRECISION;
+
+        // Longs pay shorts 1 per base, twice
+        market.apply_funding_rate(PRECISION as i128, 3_600).unwrap();
+        market.apply_funding_rate(PRECISION as i128, 7_200).unwrap();
+        long.settle_funding_payment(&mut market).unwrap();
+        short.settle_funding_payment(&mut market).unwrap();
+        assert_eq!(long.collateral, 8_000_000);
+        assert_eq!(short.collateral, 12_000_000);
+        assert_eq!(long.positions[0].last_settled_funding_ts, 7_200);
+
+        // Settled funding is not charged again
+        long.settle_funding_payment(&mut market).unwrap();
+        assert_eq!(long.collateral, 8_000_000);
+    }
+
+    #[test]
+    fn trigger_orders_fire_at_or_beyond_the_trigger_price() {
+        let stop_loss = TriggerOrder {
+            trigger_price: 90 * PRECISION,
+            condition: TriggerCondition::Below as u8,
+            ..Default::default()
+        };
+        assert!(stop_loss.is_triggered(90 * PRECISION).unwrap());
+        assert!(stop_loss.is_triggered(89 * PRECISION).unwrap());
+        assert!(!stop_loss.is_triggered(90 * PRECISION + 1).unwrap());
+
+        let take_profit = TriggerOrder {
+            trigger_price: 110 * PRECISION,
+            condition: TriggerCondition::Above as u8,
+            ..Default::default()
+        };
+        assert!(take_profit.is_triggered(110 * PRECISION).unwrap());
+        assert!(!take_profit.is_triggered(109 * PRECISION).unwrap());
+
+        let corrupt = TriggerOrder { condition: 2, ..Default::default() };
+        assert!(corrupt.is_triggered(PRECISION).is_err());
+    }
+
+    #[test]
+    fn fills_realize_pnl_on_the_closed_part_and_reopen_a_flip_at_the_fill_price() {
+        let mut position = Position::default();
+
+        // Buy 2 at 100, then 2 more at 110: the entry averages 105
+        assert_eq!(position.apply_fill(2 * PRECISION as i128, 200 * PRECISION).unwrap(), 0);
+        assert_eq!(position.apply_fill(2 * PRECISION as i128, 220 * PRECISION).unwrap(), 0);
+        assert_eq!(position.average_entry_price, 105 * PRECISION);
+
+        // Sell 1 at 115 realizes 10 and keeps the entry
+        assert_eq!(position.apply_fill(-(PRECISION as i128), 115 * PRECISION).unwrap(), 10 * PRECISION as i128);
+        assert_eq!(position.base_asset_amount, 3 * PRECISION as i128);
+        assert_eq!(position.quote_asset_amount, 315 * PRECISION);
+        assert_eq!(position.average_entry_price, 105 * PRECISION);
+
+        // Sell 5 at 95 closes 3 for a loss of 30 and opens a short of 2 at 95
+        assert_eq!(position.apply_fill(-5 * PRECISION as i128, 475 * PRECISION).unwrap(), -30 * PRECISION as i128);
+        assert_eq!(position.base_asset_amount, -2 * PRECISION as i128);
+        assert_eq!(position.quote_asset_amount, 190 * PRECISION);
+        assert_eq!(position.average_entry_price, 95 * PRECISION);
+        assert_eq!(position.realized_pnl, -20 * PRECISION as i128);
+
+        // Buying the short back at 90 realizes 10 and flattens it
+        assert_eq!(position.apply_fill(2 * PRECISION as i128, 180 * PRECISION).unwrap(), 10 * PRECISION as i128);
+        assert_eq!(position.base_asset_amount, 0);
+        assert_eq!(position.quote_asset_amount, 0);
+        assert_eq!(position.average_entry_price, 0);
+    }
+
+    #[test]
+    fn twap_slices_are_signed_by_side_and_the_last_takes_the_rest() {
+        let mut twap_order = TwapOrder {
+            base_asset_amount_remaining: 5 * PRECISION / 2,
+            slice_base_asset_amount: PRECISION,
+            is_long: true,
+            is_active: true,
+            ..Default::default()
+        };
+        assert_eq!(twap_order.get_next_slice(), PRECISION as i128);
+
+        twap_order.base_asset_amount_remaining = PRECISION / 2;
+        assert_eq!(twap_order.get_next_slice(), (PRECISION / 2) as i128);
+
+        twap_order.is_long = false;
+        assert_eq!(twap_order.get_next_slice(), -((PRECISION / 2) as i128));
+    }
 }
@@ -1,11 +1,20 @@
 use anchor_lang::prelude::*;
 use pyth_sdk_solana::{load_price_feed_from_account_info, PriceFeed};
-use crate::state::constants::{ORACLE_STALENESS_THRESHOLD, PRECISION};
+use crate::state::constants::{BPS_PRECISION, ORACLE_STALENESS_THRESHOLD, PRECISION};
 use crate::state::market::Market;
 use crate::state::user::User;
 use crate::error::PerpError;
 
 pub fn validate_oracle_price(oracle_info: &AccountInfo, clock: &Clock) -> Result<u128> {
+    Ok(validate_oracle_price_and_confidence(oracle_info, clock)?.0)
+}
+
+/// Validates the oracle like `validate_oracle_price`, also returning its confidence interval.
+/// Both are in price precision.
+pub fn validate_oracle_price_and_confidence(
+    oracle_info: &AccountInfo,
+    clock: &Clock,
+) -> Result<(u128, u128)> {
     let price_feed = load_price_feed_from_account_info(oracle_info)
         .map_err(|_| error!(PerpError::InvalidOraclePrice))?;
 
@@ -22,8 +31,12 @@ pub fn validate_oracle_price(oracle_info: &AccountInfo, clock: &Clock) -> Result
         .checked_mul(PRECISION)
         .and_then(|p| p.checked_div(scale_factor))
         .ok_or(PerpError::MathOverflow)?;
+    let oracle_confidence_u128 = (price.conf as u128)
+        .checked_mul(PRECISION)
+        .and_then(|c| c.checked_div(scale_factor))
+        .ok_or(PerpError::MathOverflow)?;
 
-    Ok(oracle_price_u128)
+    Ok((oracle_price_u128, oracle_confidence_u128))
 }
 
 pub fn validate_user_not_locked(user: &User) -> Result<()> {
@@ -35,3 +48,134 @@ pub fn validate_market_not_paused(market: &Market) -> Result<()> {
     require!(!market.paused, PerpError::MarketPaused);
     Ok(())
 }
+
+/// Returns true if an order with expiry `max_ts` (zero for none) can no longer execute at `now`.
+pub fn is_expired(max_ts: i64, now: i64) -> bool {
+    max_ts != 0 && now > max_ts
+}
+
+pub fn validate_order_not_expired(max_ts: i64, now: i64) -> Result<()> {
+    require!(!is_expired(max_ts, now), PerpError::OrderExpired);
+    Ok(())
+}
+
+/// Validates an order's optional expiry, which must be a positive timestamp that has not passed
+/// at `now`, and returns it as stored on the order (zero for none).
+pub fn validate_max_ts(max_ts: Option<i64>, now: i64) -> Result<i64> {
+    let Some(max_ts) = max_ts else {
+        return Ok(0);
+    };
+    require_gt!(max_ts, 0, PerpError::InvalidOrderExpiry);
+    validate_order_not_expired(max_ts, now)?;
+
+    Ok(max_ts)
+}
+
+/// Checks a vAMM fill left the mark price within the market's maximum divergence from
+/// `oracle_price`. A fill that brought the mark closer to the oracle than `mark_price_before`
+/// passes even outside the band, so the market can always trade back toward the oracle.
+pub fn validate_mark_oracle_divergence(
+    market: &Market,
+    oracle_price: u128,
+    mark_price_before: u128,
+) -> Result<()> {
+    if market.max_mark_oracle_divergence_bps == 0 {
+        return Ok(());
+    }
+
+    let divergence = market.get_mark_price()?.abs_diff(oracle_price);
+    if divergence <= mark_price_before.abs_diff(oracle_price) {
+        return Ok(());
+    }
+
+    let max_divergence = oracle_price
+        .checked_mul(market.max_mark_oracle_divergence_bps as u128)
+        .and_then(|d| d.checked_div(BPS_PRECISION))
+        .ok_or(PerpError::MathOverflow)?;
+    require_gte!(max_divergence, divergence, PerpError::MarkOracleDivergenceExceeded);
+
+    Ok(())
+}
+
+/// Checks a fill away from the vAMM is priced within the market's maximum divergence from
+/// `oracle_price`.
+pub fn validate_fill_price_within_oracle_band(
+    market: &Market,
+    oracle_price: u128,
+    fill_price: u128,
+) -> Result<()> {
+    if market.max_mark_oracle_divergence_bps == 0 {
+        return Ok(());
+    }
+
+    let max_divergence = oracle_price
+        .checked_mul(market.max_mark_oracle_divergence_bps as u128)
+        .and_then(|d| d.checked_div(BPS_PRECISION))
+        .ok_or(PerpError::MathOverflow)?;
+    require_gte!(
+        max_divergence,
+        fill_price.abs_diff(oracle_price),
+        PerpError::FillPriceOutsideOracleBand
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::constants::PEG_PRECISION;
+
+    #[test]
+    fn fill_price_must_be_within_the_oracle_band() {
+        let market = Market {
+            max_mark_oracle_divergence_bps: 500,
+            ..Default::default()
+        };
+        let oracle_price = 100 * PRECISION;
+
+        assert!(validate_fill_price_within_oracle_band(&market, oracle_price, 105 * PRECISION).is_ok());
+        assert!(validate_fill_price_within_oracle_band(&market, oracle_price, 95 * PRECISION).is_ok());
+        assert!(validate_fill_price_within_oracle_band(&market, oracle_price, 105 * PRECISION + 1).is_err());
+        assert!(validate_fill_price_within_oracle_band(&market, oracle_price, 90 * PRECISION).is_err());
+
+        // A zero band disables the check
+        let market = Market::default();
+        assert!(validate_fill_price_within_oracle_band(&market, oracle_price, 50 * PRECISION).is_ok());
+    }
+
+    #[test]
+    fn order_expiry_must_be_positive_and_not_passed() {
+        assert_eq!(validate_max_ts(None, 1_000).unwrap(), 0);
+        assert_eq!(validate_max_ts(Some(1_000), 1_000).unwrap(), 1_000);
+        assert!(validate_max_ts(Some(999), 1_000).is_err());
+        assert!(validate_max_ts(Some(0), 1_000).is_err());
+        assert!(validate_max_ts(Some(-1), 0).is_err());
+
+        // Zero is stored for orders without an expiry, which never expire
+        assert!(!is_expired(0, i64::MAX));
+        assert!(!is_expired(1_000, 1_000));
+        assert!(is_expired(1_000, 1_001));
+    }
+
+    #[test]
+    fn vamm_fills_must_leave_the_mark_within_the_band_or_closer_to_the_oracle() {
+        let market_at = |mark_price: u128| Market {
+            amm_base_asset_reserve: 1_000 * PRECISION,
+            amm_quote_asset_reserve: mark_price * 10,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_mark_oracle_divergence_bps: 500,
+            ..Default::default()
+        };
+        let oracle_price = 100 * PRECISION;
+
+        assert!(validate_mark_oracle_divergence(&market_at(105 * PRECISION), oracle_price, oracle_price).is_ok());
+        assert!(validate_mark_oracle_divergence(&market_at(106 * PRECISION), oracle_price, 103 * PRECISION).is_err());
+        assert!(validate_mark_oracle_divergence(&market_at(94 * PRECISION), oracle_price, oracle_price).is_err());
+        // Outside the band but closer to the oracle than before the fill
+        assert!(validate_mark_oracle_divergence(&market_at(106 * PRECISION), oracle_price, 110 * PRECISION).is_ok());
+
+        let unbounded = Market { max_mark_oracle_divergence_bps: 0, ..market_at(150 * PRECISION) };
+        assert!(validate_mark_oracle_divergence(&unbounded, oracle_price, oracle_price).is_ok());
+    }
+}
@@ -10,6 +10,7 @@ import {
   TOKEN_PROGRAM_ID,
   createMint,
   createAccount,
+  getAccount,
   mintTo,
 } from '@solana/spl-token';
 import { assert } from 'chai';
@@ -44,6 +45,20 @@ async function createPythAccount(
   account.data = pythData;
 }
 
+async function expectError(promise: Promise<unknown>, code: string) {
+  try {
+    await promise;
+  } catch (err) {
+    assert.include(err.toString(), code);
+    return;
+  }
+  assert.fail(`expected ${code}`);
+}
+
+function sleep(ms: number) {
+  return new Promise((resolve) => setTimeout(resolve, ms));
+}
+
 describe('perp_dex', () => {
   const provider = anchor.AnchorProvider.env();
   anchor.setProvider(provider);
@@ -57,6 +72,19 @@ describe('perp_dex', () => {
   let programState: PublicKey;
   let userAccount: PublicKey;
 
+  const [marketKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from('market'), new anchor.BN(0).toArrayLike(Buffer, 'le', 2)],
+    program.programId
+  );
+  const [orderBook] = PublicKey.findProgramAddressSync(
+    [Buffer.from('order_book'), new anchor.BN(0).toArrayLike(Buffer, 'le', 2)],
+    program.programId
+  );
+  const marketOracleAccounts = [
+    { pubkey: marketKey, isSigner: false, isWritable: false },
+    { pubkey: MOCK_PYTH_PRICE_FEED.publicKey, isSigner: false, isWritable: false },
+  ];
+
   before(async () => {
     usdcMint = await createMint(provider.connection, admin.payer, admin.publicKey, null, 6);
     userCollateralAccount = await createAccount(
@@ -179,11 +207,12 @@ describe('perp_dex', () => {
     const limitPrice = new anchor.BN('101000000000');
 
     await program.methods
-      .openPosition(baseAssetAmount, limitPrice)
+      .openPosition({ baseAssetAmount, limitPrice, reduceOnly: false, orderType: { limit: {} }, maxTs: null })
       .accounts({
         authority: admin.publicKey,
         userAccount,
         market: marketKey,
+        oraclePriceFeed: MOCK_PYTH_PRICE_FEED.publicKey,
       })
       .remainingAccounts([
         {
@@ -205,4 +234,412 @@ describe('perp_dex', () => {
     assert.equal(position.baseAssetAmount.toString(), baseAssetAmount.toString());
     assert.isTrue(position.quoteAssetAmount.gtn(0));
   });
+
+  it('Caps withdrawals at free collateral', async () => {
+    await expectError(
+      program.methods
+        .withdrawCollateral(new anchor.BN(100 * 10 ** 6))
+        .accounts({
+          authority: admin.publicKey,
+          userAccount,
+          programState,
+          collateralVault,
+          userCollateralAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts(marketOracleAccounts)
+        .rpc(),
+      'WithdrawalCausesMarginCall'
+    );
+  });
+
+  it('Refuses to migrate accounts already in the current layout', async () => {
+    await expectError(
+      program.methods
+        .migrateUser(admin.publicKey)
+        .accounts({
+          payer: admin.publicKey,
+          userAccount,
+          systemProgram: SystemProgram.programId,
+        })
+        .remainingAccounts([{ pubkey: marketKey, isSigner: false, isWritable: true }])
+        .rpc(),
+      'AccountAlreadyMigrated'
+    );
+
+    await expectError(
+      program.methods
+        .migrateMarket(0)
+        .accounts({
+          admin: admin.publicKey,
+          programState,
+          market: marketKey,
+          oraclePriceFeed: MOCK_PYTH_PRICE_FEED.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc(),
+      'AccountAlreadyMigrated'
+    );
+  });
+
+  it('Charges the trade fee into the fee pool', async () => {
+    const market = await program.account.market.fetch(marketKey);
+    assert.isTrue(market.totalFee.gtn(0));
+    assert.isTrue(market.feePool.gtn(0));
+  });
+
+  it('Keeps the fee pool backing the vAMM exposure', async () => {
+    const market = await program.account.market.fetch(marketKey);
+
+    await expectError(
+      program.methods
+        .withdrawFeePool(market.feePool.addn(1))
+        .accounts({
+          admin: admin.publicKey,
+          programState,
+          market: marketKey,
+          collateralVault,
+          recipient: userCollateralAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc(),
+      'InsufficientFeePool'
+    );
+  });
+
+  it('Stakes into and unstakes from the insurance fund', async () => {
+    const [insuranceVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from('insurance_vault'), usdcMint.toBuffer()],
+      program.programId
+    );
+    const [insuranceFundStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from('insurance_fund_stake'), admin.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializeInsuranceFund(new anchor.BN(0))
+      .accounts({
+        admin: admin.publicKey,
+        programState,
+        usdcMint,
+        insuranceVault,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      })
+      .rpc();
+
+    await program.methods
+      .initializeInsuranceFundStake()
+      .accounts({
+        authority: admin.publicKey,
+        insuranceFundStake,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    await program.methods
+      .addInsuranceFundStake(new anchor.BN(10 * 10 ** 6))
+      .accounts({
+        authority: admin.publicKey,
+        insuranceFundStake,
+        programState,
+        insuranceVault,
+        userCollateralAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const stake = await program.account.insuranceFundStake.fetch(insuranceFundStake);
+    const state = await program.account.state.fetch(programState);
+    assert.isTrue(stake.shares.gtn(0));
+    assert.equal(stake.sharesEpoch.toString(), state.insuranceFund.sharesEpoch.toString());
+
+    const balanceBefore = (await getAccount(provider.connection, userCollateralAccount)).amount;
+    const unstakeShares = stake.shares.divn(4);
+    await program.methods
+      .requestRemoveInsuranceFundStake(unstakeShares)
+      .accounts({
+        authority: admin.publicKey,
+        insuranceFundStake,
+        programState,
+        insuranceVault,
+      })
+      .rpc();
+    await program.methods
+      .removeInsuranceFundStake()
+      .accounts({
+        authority: admin.publicKey,
+        insuranceFundStake,
+        programState,
+        insuranceVault,
+        userCollateralAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const unstaked = await program.account.insuranceFundStake.fetch(insuranceFundStake);
+    const balanceAfter = (await getAccount(provider.connection, userCollateralAccount)).amount;
+    assert.equal(unstaked.shares.toString(), stake.shares.sub(unstakeShares).toString());
+    assert.isTrue(unstaked.lastWithdrawRequestShares.eqn(0));
+    // Nothing moved the vault, so the shares redeem at the staked price
+    assert.equal((balanceAfter - balanceBefore).toString(), (2.5 * 10 ** 6).toString());
+  });
+
+  it('Refuses to liquidate a healthy user', async () => {
+    const liquidator = new Keypair();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(liquidator.publicKey, 10 ** 9)
+    );
+    const [liquidatorAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from('user'), liquidator.publicKey.toBuffer()],
+      program.programId
+    );
+    const [insuranceVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from('insurance_vault'), usdcMint.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .createUser()
+      .accounts({
+        authority: liquidator.publicKey,
+        userAccount: liquidatorAccount,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([liquidator])
+      .rpc();
+
+    await expectError(
+      program.methods
+        .liquidate(0)
+        .accounts({
+          liquidator: liquidator.publicKey,
+          liquidatorAccount,
+          userAccount,
+          market: marketKey,
+          orderBook: null,
+          programState,
+          collateralVault,
+          insuranceVault,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts(marketOracleAccounts)
+        .signers([liquidator])
+        .rpc(),
+      'PositionNotLiquidatable'
+    );
+  });
+
+  it('Enforces the minimum order size on the order book', async () => {
+    await program.methods
+      .initializeOrderBook(0)
+      .accounts({
+        admin: admin.publicKey,
+        programState,
+        market: marketKey,
+        orderBook,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const minOrderSize = new anchor.BN('100000000');
+    await program.methods
+      .updateMarketMinOrderSize(minOrderSize)
+      .accounts({
+        admin: admin.publicKey,
+        programState,
+        market: marketKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKey);
+    assert.equal(market.minOrderSize.toString(), minOrderSize.toString());
+
+    await expectError(
+      program.methods
+        .placeOrder(0, new anchor.BN('50000000'), new anchor.BN('90000000000'), { limit: {} }, null)
+        .accounts({
+          authority: admin.publicKey,
+          userAccount,
+          market: marketKey,
+          orderBook,
+        })
+        .remainingAccounts(marketOracleAccounts)
+        .rpc(),
+      'OrderTooSmall'
+    );
+  });
+
+  it('Begins and cancels a taker auction', async () => {
+    const [takerAuction] = PublicKey.findProgramAddressSync(
+      [Buffer.from('taker_auction'), userAccount.toBuffer()],
+      program.programId
+    );
+    const maxTs = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+    await program.methods
+      .beginTakerAuction(0, new anchor.BN('1000000000'), new anchor.BN('101000000000'), maxTs)
+      .accounts({
+        authority: admin.publicKey,
+        userAccount,
+        market: marketKey,
+        takerAuction,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const auction = await program.account.takerAuction.fetch(takerAuction);
+    assert.equal(auction.maxTs.toString(), maxTs.toString());
+
+    await program.methods
+      .cancelTakerAuction()
+      .accounts({
+        authority: admin.publicKey,
+        takerAuction,
+      })
+      .rpc();
+
+    assert.isNull(await program.account.takerAuction.fetchNullable(takerAuction));
+  });
+
+  it('Records the side a trigger order reduces', async () => {
+    await program.methods
+      .placeTriggerOrder({
+        marketIndex: 0,
+        triggerPrice: new anchor.BN('90000000000'),
+        condition: { below: {} },
+        source: { oracle: {} },
+        baseAssetAmount: new anchor.BN('1000000000'),
+        limitPrice: new anchor.BN('89000000000'),
+        maxTs: null,
+      })
+      .accounts({
+        authority: admin.publicKey,
+        userAccount,
+      })
+      .rpc();
+
+    const user = await program.account.user.fetch(userAccount);
+    const triggerOrder = user.triggerOrders.find((o) => o.isActive);
+    assert.isTrue(triggerOrder.reducesLong);
+  });
+
+  it('Rejects a non-positive order expiry', async () => {
+    await expectError(
+      program.methods
+        .openPosition({
+          baseAssetAmount: new anchor.BN('1000000000'),
+          limitPrice: new anchor.BN('101000000000'),
+          reduceOnly: false,
+          orderType: { limit: {} },
+          maxTs: new anchor.BN(0),
+        })
+        .accounts({
+          authority: admin.publicKey,
+          userAccount,
+          market: marketKey,
+          oraclePriceFeed: MOCK_PYTH_PRICE_FEED.publicKey,
+        })
+        .remainingAccounts(marketOracleAccounts)
+        .rpc(),
+      'InvalidOrderExpiry'
+    );
+  });
+
+  it('Prunes expired resting orders', async () => {
+    const baseAssetAmount = new anchor.BN('1000000000');
+    const maxTs = new anchor.BN(Math.floor(Date.now() / 1000) + 3);
+
+    await program.methods
+      .placeOrder(0, baseAssetAmount, new anchor.BN('90000000000'), { limit: {} }, maxTs)
+      .accounts({
+        authority: admin.publicKey,
+        userAccount,
+        market: marketKey,
+        orderBook,
+      })
+      .remainingAccounts(marketOracleAccounts)
+      .rpc();
+
+    let book = await program.account.orderBook.fetch(orderBook);
+    let user = await program.account.user.fetch(userAccount);
+    assert.equal(book.numBids, 1);
+    assert.equal(user.positions[0].openBids.toString(), baseAssetAmount.toString());
+
+    await sleep(6000);
+    await program.methods
+      .pruneExpiredOrders(0)
+      .accounts({ orderBook })
+      .remainingAccounts([{ pubkey: userAccount, isSigner: false, isWritable: true }])
+      .rpc();
+
+    book = await program.account.orderBook.fetch(orderBook);
+    user = await program.account.user.fetch(userAccount);
+    assert.equal(book.numBids, 0);
+    assert.equal(user.positions[0].openBids.toString(), '0');
+  });
+
+  it('Stores a TWAP order expiry', async () => {
+    const maxTs = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+    await program.methods
+      .placeTwapOrder({
+        marketIndex: 0,
+        baseAssetAmount: new anchor.BN('1000000000'),
+        sliceBaseAssetAmount: new anchor.BN('500000000'),
+        interval: new anchor.BN(60),
+        limitPrice: new anchor.BN('101000000000'),
+        maxTs,
+      })
+      .accounts({
+        authority: admin.publicKey,
+        userAccount,
+      })
+      .rpc();
+
+    let user = await program.account.user.fetch(userAccount);
+    assert.equal(user.twapOrders[0].maxTs.toString(), maxTs.toString());
+
+    await program.methods
+      .cancelTwapOrder(0)
+      .accounts({
+        authority: admin.publicKey,
+        userAccount,
+      })
+      .rpc();
+
+    user = await program.account.user.fetch(userAccount);
+    assert.isFalse(user.twapOrders[0].isActive);
+  });
+
+  it('Locks and releases margin for a limit order', async () => {
+    const baseAssetAmount = new anchor.BN('1000000000');
+
+    await program.methods
+      .placeLimitOrder(0, baseAssetAmount, new anchor.BN('90000000000'), null)
+      .accounts({
+        authority: admin.publicKey,
+        userAccount,
+        market: marketKey,
+      })
+      .remainingAccounts(marketOracleAccounts)
+      .rpc();
+
+    let user = await program.account.user.fetch(userAccount);
+    const limitOrder = user.limitOrders.find((o) => o.isActive);
+    assert.equal(user.positions[0].openBids.toString(), baseAssetAmount.toString());
+
+    await program.methods
+      .cancelLimitOrder(limitOrder.orderId)
+      .accounts({
+        authority: admin.publicKey,
+        userAccount,
+      })
+      .rpc();
+
+    user = await program.account.user.fetch(userAccount);
+    assert.equal(user.positions[0].openBids.toString(), '0');
+  });
 });